@@ -1,86 +1,197 @@
-use crate::prelude::*;
-use bytes::BytesMut;
-use rodio::{Decoder, Sink};
-use std::io::Cursor;
+use crate::{bot::Interrupt, output::Output, prelude::*};
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use symphonia::{
+    core::{
+        audio::SampleBuffer,
+        codecs::{Decoder, DecoderOptions},
+        errors::Error as DecodeError,
+        formats::{FormatOptions, FormatReader},
+        io::{MediaSourceStream, ReadOnlySource},
+    },
+    default::{codecs::MpaDecoder, formats::MpaReader},
+};
 use tokio::{
     self,
-    io::{self, AsyncReadExt},
-    sync::watch,
-    time::{self, Duration, Instant},
+    io::DuplexStream,
+    sync::{mpsc::Receiver, watch},
+    task::{self, JoinSet},
+    time::{self, Duration},
 };
+use tokio_util::io::SyncIoBridge;
 
+/// Plays the audio of the replies as it's being decoded and notifies
+/// audio_done once the sink has finished playing all of it.
 pub async fn play(
-    mut audio_rd: io::DuplexStream,
-    sink: Sink,
-    audio_done: watch::Sender<bool>,
+    replies: &mut Receiver<DuplexStream>,
+    sink: Arc<dyn Output>,
+    interval: Duration,
+    audio_done: &watch::Sender<bool>,
+    mut interrupt: watch::Receiver<Interrupt>,
     mut done: watch::Receiver<bool>,
 ) -> Result<()> {
     println!("launching audio player");
-    let mut audio_data = BytesMut::new();
-    // TODO: make this a cli switch as this value has been picked rather arbitrarily
-    let interval_duration = Duration::from_millis(AUDIO_INTERVAL);
-    let mut interval = time::interval(interval_duration);
-    let mut last_play_time = Instant::now();
-    let mut has_played_audio = false;
+    // NOTE: we decode a single reply at a time.
+    let mut decoder = JoinSet::new();
+    let mut cancelled = Arc::new(AtomicBool::new(false));
+    // NOTE: set once the whole reply has been decoded.
+    let mut decoded = false;
+
+    let mut interval = time::interval(interval);
 
     loop {
         tokio::select! {
+            biased;
             _ = done.changed() => {
                 if *done.borrow() {
-                    break;
+                    sink.stop();
+                    return Ok(())
                 }
             }
-            result = audio_rd.read_buf(&mut audio_data) => {
-                if let Ok(chunk) = result {
-                    if chunk == 0 {
-                        break;
-                    }
-                    if audio_data.len() > AUDIO_BUFFER_SIZE {
-                        // NOTE: this avoids unnecessary data duplication and manages the buffer efficiently
-                        let cursor = Cursor::new(audio_data.split_to(AUDIO_BUFFER_SIZE).freeze().to_vec());
-                        match Decoder::new(cursor) {
-                            Ok(source) => {
-                                sink.append(source);
-                                last_play_time = Instant::now();
-                                has_played_audio = true;
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to decode received audio: {}", e);
-                            }
-                        }
+            _ = interrupt.changed() => {
+                // NOTE: on shutdown the sentence being spoken is played out.
+                if *interrupt.borrow_and_update() == Interrupt::Shutdown {
+                    continue;
+                }
+                cancelled.store(true, Ordering::SeqCst);
+                decoded = false;
+                sink.stop();
+            }
+            Some(res) = decoder.join_next() => {
+                // NOTE: the audio of an interrupted reply may well be cut mid-frame.
+                if let Err(e) = res? {
+                    if !cancelled.load(Ordering::SeqCst) {
+                        return Err(e);
                     }
                 }
+                decoded = !cancelled.load(Ordering::SeqCst);
+            }
+            rd = replies.recv(), if decoder.is_empty() => {
+                let Some(rd) = rd else {
+                    // NOTE: the TTS stream has been closed; play what's left.
+                    task::spawn_blocking(move || sink.sleep_until_end()).await?;
+                    return Ok(())
+                };
+                cancelled = Arc::new(AtomicBool::new(false));
+                let rd = SyncIoBridge::new(rd);
+                let (sink, cancelled) = (sink.clone(), cancelled.clone());
+                decoder.spawn_blocking(move || decode(rd, sink.as_ref(), &cancelled));
             }
             _ = interval.tick() => {
-                // No audio data received in the past interval_duration ms and we've
-                // already played some audio -- that means we can proceed with dialogue
-                // by writing a followup question into JetStream through jet::writer.
-                if has_played_audio && last_play_time.elapsed() >= interval_duration && sink.empty() {
-                    if !audio_data.is_empty() {
-                        let cursor = Cursor::new(audio_data.clone().freeze().to_vec());
-                        if let Ok(source) = Decoder::new(cursor) {
-                            sink.append(source);
-                            audio_data.clear();
-                        }
-                    }
-                    sink.sleep_until_end();
+                // The sink has played all the audio of the reply -- that means we can proceed
+                // with dialogue by writing a followup question into JetStream through jet::writer.
+                if decoded && sink.empty() {
+                    decoded = false;
                     // NOTE: notify jet::writer
                     audio_done.send(true)?;
-                    has_played_audio = false;
                 }
             }
         }
     }
+}
 
-    // Flush any remaining data
-    if !audio_data.is_empty() {
-        let cursor = Cursor::new(audio_data.clone().to_vec());
-        if let Ok(source) = Decoder::new(cursor) {
-            sink.append(source);
+/// Decodes the audio stream and appends the audio to the sink as soon as it's decoded.
+/// The stream is either a sequence of WAV files or a continuous MP3 stream.
+/// Once cancelled, the rest of the stream is decoded, but not played.
+fn decode<R>(mut r: R, sink: &dyn Output, cancelled: &AtomicBool) -> Result<()>
+where
+    R: Read + Send + Sync + 'static,
+{
+    loop {
+        let mut magic = Vec::with_capacity(4);
+        r.by_ref().take(4).read_to_end(&mut magic)?;
+        if magic.is_empty() {
+            return Ok(());
+        }
+        if magic != b"RIFF" {
+            return decode_mp3(io::Cursor::new(magic).chain(r), sink, cancelled);
         }
+        decode_wav(magic.chain(&mut r), sink, cancelled)?;
     }
-    if !sink.empty() {
-        sink.sleep_until_end();
+}
+
+/// Decodes a single WAV file from the stream, leaving the rest of the stream unread.
+fn decode_wav<R: Read>(r: R, sink: &dyn Output, cancelled: &AtomicBool) -> Result<()> {
+    let mut wav = hound::WavReader::new(r)?;
+    let spec = wav.spec();
+    let samples: Box<dyn Iterator<Item = hound::Result<f32>>> = match spec.sample_format {
+        hound::SampleFormat::Float => Box::new(wav.samples::<f32>()),
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            Box::new(
+                wav.samples::<i32>()
+                    .map(move |s| s.map(|s| s as f32 / scale)),
+            )
+        }
+    };
+
+    // NOTE: append the audio in frames of about 100ms so the playback
+    // starts without waiting for the whole file to arrive.
+    let frame = (spec.sample_rate as usize / 10).max(1) * spec.channels as usize;
+    let mut samples = samples.peekable();
+    while samples.peek().is_some() {
+        let data = samples
+            .by_ref()
+            .take(frame)
+            .collect::<hound::Result<Vec<f32>>>()?;
+        append(sink, cancelled, spec.channels, spec.sample_rate, data)?;
     }
     Ok(())
 }
+
+/// Decodes the MP3 stream until it's closed.
+fn decode_mp3<R>(r: R, sink: &dyn Output, cancelled: &AtomicBool) -> Result<()>
+where
+    R: Read + Send + Sync + 'static,
+{
+    let mss = MediaSourceStream::new(Box::new(ReadOnlySource::new(r)), Default::default());
+    let mut format = MpaReader::try_new(mss, &FormatOptions::default())?;
+    let track = format.default_track().ok_or("no audio track found")?;
+    let mut decoder = MpaDecoder::try_new(&track.codec_params, &DecoderOptions::default())?;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match decoder.decode(&packet) {
+            Ok(audio) => {
+                let spec = *audio.spec();
+                let mut buf = SampleBuffer::<f32>::new(audio.capacity() as u64, spec);
+                buf.copy_interleaved_ref(audio);
+                append(
+                    sink,
+                    cancelled,
+                    spec.channels.count() as u16,
+                    spec.rate,
+                    buf.samples().to_vec(),
+                )?;
+            }
+            Err(DecodeError::DecodeError(e)) => {
+                eprintln!("Failed to decode received audio: {}", e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn append(
+    sink: &dyn Output,
+    cancelled: &AtomicBool,
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<f32>,
+) -> Result<()> {
+    if cancelled.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    sink.append(channels, sample_rate, samples)
+}
@@ -0,0 +1,282 @@
+use crate::{
+    audio,
+    envelope::Envelope,
+    jet, llm, output,
+    prelude::*,
+    record, signal, stop,
+    supervisor::{self, Supervisor, Task},
+    transcript, tts, turn,
+};
+use rodio::OutputStreamHandle;
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    self,
+    io::DuplexStream,
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub jet: jet::Config,
+    pub llm: llm::Config,
+    pub tts: tts::Config,
+    pub audio: output::Config,
+    pub text_only: bool,
+    /// Cut the reply short when another message arrives.
+    pub barge_in: bool,
+    pub timing: turn::Timing,
+    pub stop: stop::Config,
+    pub supervisor: supervisor::Config,
+    pub transcript: Option<PathBuf>,
+    pub recording: Option<record::Config>,
+}
+
+/// Interrupt cuts the reply the bot is giving short: it stops the audio
+/// and cancels the generation and the speech synthesis of the reply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interrupt {
+    /// Nothing has cut the reply short.
+    #[default]
+    None,
+    /// Another message has arrived; the bot replies to it instead.
+    Message,
+    /// The operator has cut the reply short; what's been said so far is published.
+    Operator,
+    /// The bot is shutting down: the sentence being spoken is finished and
+    /// what's been said so far is published, unless partial replies are discarded.
+    Shutdown,
+}
+
+/// Bot wires the JetStream, LLM, TTS and audio workers together.
+pub struct Bot {
+    stream: jet::Stream,
+    llm: llm::LLM,
+    tts: Option<tts::TTS>,
+    audio: output::Config,
+    recorder: Option<record::Recorder>,
+    transcript: Option<transcript::Transcript>,
+    interrupt: watch::Sender<Interrupt>,
+    supervisor: Supervisor,
+}
+
+impl Bot {
+    pub async fn new(c: Config, t: Arc<dyn jet::Transport>) -> Result<Self> {
+        let name = c.jet.durable_name.clone();
+        let coord = turn::Coordinator::new(
+            name.clone(),
+            c.llm.table.clone(),
+            c.timing,
+            c.barge_in,
+            c.jet.turn,
+        );
+        let budget = stop::Budget::new(c.stop, name.clone());
+        let stream = jet::Stream::new(t, c.jet, coord, budget).await?;
+        let recorder = match c.recording {
+            Some(rc) if !c.text_only => Some(record::Recorder::new(rc, name, stream.conv.clone())?),
+            _ => None,
+        };
+        let llm = llm::LLM::new(c.llm)?;
+        llm.prepare().await?;
+        Ok(Bot {
+            stream,
+            llm,
+            tts: (!c.text_only).then(|| tts::TTS::new(c.tts)),
+            audio: c.audio,
+            recorder,
+            transcript: c
+                .transcript
+                .as_deref()
+                .map(transcript::Transcript::open)
+                .transpose()?,
+            interrupt: watch::Sender::new(Interrupt::default()),
+            supervisor: Supervisor::new(c.supervisor),
+        })
+    }
+
+    /// Returns the sender which interrupts the bot.
+    pub fn interrupter(&self) -> watch::Sender<Interrupt> {
+        self.interrupt.clone()
+    }
+
+    /// Runs the bot workers until any of them fails for good, until the shutdown is over
+    /// or until the conversation ends. The audio device is only used by the rodio audio output.
+    pub async fn run(
+        self,
+        device: Option<&OutputStreamHandle>,
+        mut shutdown: watch::Receiver<signal::Shutdown>,
+    ) -> Result<()> {
+        // NOTE: the bot stops on its own once its conversation ends,
+        // while the other bots in the same process carry on.
+        let stop = watch::Sender::new(false);
+        let done = stop.subscribe();
+        let (prompts_tx, mut prompts_rx) = mpsc::channel::<Envelope>(32);
+        let (jet_chunks_tx, mut jet_chunks_rx) = mpsc::channel::<llm::Chunk>(32);
+        let supervisor = self.supervisor;
+        let mut workers: JoinSet<Result<()>> = JoinSet::new();
+
+        let (forward, mut stopped) = (stop.clone(), stop.subscribe());
+        let interrupt = self.interrupt.clone();
+        workers.spawn(async move {
+            loop {
+                tokio::select! {
+                    res = shutdown.changed() => {
+                        // NOTE: nobody can shut us down anymore.
+                        if res.is_err() {
+                            return Ok(());
+                        }
+                        match *shutdown.borrow_and_update() {
+                            signal::Shutdown::Running => {}
+                            signal::Shutdown::Draining => {
+                                interrupt.send_replace(Interrupt::Shutdown);
+                            }
+                            signal::Shutdown::Now => {
+                                forward.send_replace(true);
+                                return Ok(());
+                            }
+                        }
+                    },
+                    _ = stopped.wait_for(|s| *s) => return Ok(()),
+                }
+            }
+        });
+
+        let (tts_chunks_tx, mut aud_done_rx) = match self.tts {
+            Some(t) => {
+                let (tts_chunks_tx, mut tts_chunks_rx) = mpsc::channel::<llm::Chunk>(32);
+                let (replies_tx, mut replies_rx) = mpsc::channel::<DuplexStream>(4);
+                let (aud_done_tx, aud_done_rx) = watch::channel(false);
+
+                let sink: Arc<dyn output::Output> = Arc::from(output::open(&self.audio, device)?);
+                let interval = self.audio.interval;
+
+                let mut tts_task = supervisor.supervise(Task::Tts, done.clone());
+                let (interrupt, done_rx) = (self.interrupt.clone(), done.clone());
+                let mut recorder = self.recorder;
+                workers.spawn(async move {
+                    while tts_task
+                        .restart(
+                            t.stream(
+                                &replies_tx,
+                                &mut tts_chunks_rx,
+                                &mut recorder,
+                                interrupt.subscribe(),
+                                done_rx.clone(),
+                            )
+                            .await,
+                        )
+                        .await?
+                    {}
+                    Ok(())
+                });
+                let mut audio_task = supervisor.supervise(Task::Audio, done.clone());
+                let (interrupt, done_rx) = (self.interrupt.clone(), done.clone());
+                workers.spawn(async move {
+                    while audio_task
+                        .restart(
+                            audio::play(
+                                &mut replies_rx,
+                                sink.clone(),
+                                interval,
+                                &aud_done_tx,
+                                interrupt.subscribe(),
+                                done_rx.clone(),
+                            )
+                            .await,
+                        )
+                        .await?
+                    {}
+                    Ok(())
+                });
+                (Some(tts_chunks_tx), Some(aud_done_rx))
+            }
+            None => (None, None),
+        };
+
+        let transcript_tx = match self.transcript {
+            Some(mut t) => {
+                let (transcript_tx, mut transcript_rx) = mpsc::channel::<Envelope>(32);
+                let mut transcript_task = supervisor.supervise(Task::Transcript, done.clone());
+                let done_rx = done.clone();
+                workers.spawn(async move {
+                    while transcript_task
+                        .restart(
+                            transcript::record(&mut t, &mut transcript_rx, done_rx.clone()).await,
+                        )
+                        .await?
+                    {}
+                    Ok(())
+                });
+                Some(transcript_tx)
+            }
+            None => None,
+        };
+
+        let mut llm_task = supervisor.supervise(Task::Llm, done.clone());
+        let (interrupt, done_rx) = (self.interrupt.clone(), done.clone());
+        let mut llm = self.llm;
+        workers.spawn(async move {
+            while llm_task
+                .restart(
+                    llm.stream(
+                        &mut prompts_rx,
+                        &jet_chunks_tx,
+                        &tts_chunks_tx,
+                        interrupt.subscribe(),
+                        done_rx.clone(),
+                    )
+                    .await,
+                )
+                .await?
+            {}
+            Ok(())
+        });
+        let mut writer_task = supervisor.supervise(Task::Writer, done.clone());
+        let (interrupt, writer, transcript) = (
+            self.interrupt.clone(),
+            self.stream.writer,
+            transcript_tx.clone(),
+        );
+        workers.spawn(async move {
+            while writer_task
+                .restart(
+                    writer
+                        .write(
+                            &mut jet_chunks_rx,
+                            &mut aud_done_rx,
+                            &transcript,
+                            interrupt.subscribe(),
+                            &stop,
+                        )
+                        .await,
+                )
+                .await?
+            {}
+            Ok(())
+        });
+        let mut reader_task = supervisor.supervise(Task::Reader, done.clone());
+        let (interrupt, mut reader) = (self.interrupt.clone(), self.stream.reader);
+        workers.spawn(async move {
+            while reader_task
+                .restart(
+                    reader
+                        .read(&prompts_tx, &transcript_tx, &interrupt, done.clone())
+                        .await,
+                )
+                .await?
+            {}
+            Ok(())
+        });
+
+        // NOTE: we bail out as soon as any of the workers fails for good;
+        // dropping the JoinSet aborts the remaining workers.
+        while let Some(res) = workers.join_next().await {
+            res??;
+        }
+        if let Some(report) = supervisor.report() {
+            println!("\n{}", report);
+        }
+        println!("\n{}", self.stream.budget.lock().unwrap().summary());
+        Ok(())
+    }
+}
@@ -1,48 +1,510 @@
-use crate::prelude::*;
-use clap::{Args, Parser};
+use crate::{envelope, jet, llm, output, prelude::*, supervisor, tts, turn};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
 
+// NOTE: every group of the flags below is a section of the config file.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct App {
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        env = "RUSTBOT_CONFIG",
+        help = "TOML config file; the environment and the flags take precedence over it"
+    )]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[command(flatten)]
-    pub prompt: Prompt,
+    pub persona: Persona,
+    #[command(flatten)]
+    pub jet: Jet,
     #[command(flatten)]
     pub llm: LLM,
     #[command(flatten)]
     pub bot: Bot,
     #[command(flatten)]
+    pub table: Table,
+    #[command(flatten)]
     pub tts: TTS,
+    #[command(flatten)]
+    pub audio: Audio,
+    #[command(flatten)]
+    pub stop: Stop,
+    #[command(flatten)]
+    pub supervisor: Supervisor,
+    #[command(flatten)]
+    pub transcript: Transcript,
+    #[command(flatten)]
+    pub recording: Recording,
 }
 
-#[derive(Args, Debug)]
-pub struct Prompt {
-    #[arg(long, default_value = DEFAULT_SEED_PROMPT, help = "instruction prompt")]
-    pub seed: Option<String>,
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the configuration.
+    #[command(subcommand)]
+    Config(Config),
 }
 
-#[derive(Args, Debug)]
-pub struct LLM {
-    #[arg(long, default_value_t = 50)]
-    #[arg(short = 't', help = "chat history size")]
-    pub hist_size: usize,
-    #[arg(short, long, default_value = DEFAULT_MODEL_NAME, help = "LLM model")]
-    pub model_name: String,
+#[derive(Subcommand, Debug)]
+pub enum Config {
+    /// Print the effective configuration as a TOML config file.
+    Dump,
 }
 
 #[derive(Args, Debug)]
-pub struct Bot {
+pub struct Persona {
+    #[arg(
+        long = "persona",
+        value_name = "NAME",
+        help = "persona file, or the name of the persona file in the persona directory; the other settings take precedence over it"
+    )]
+    pub profile: Option<String>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = PERSONA_DIR,
+        help = "directory of the persona files; the in-process bots use the persona files named after them"
+    )]
+    pub persona_dir: PathBuf,
     #[arg(short, long = "bot-name", default_value = BOT_NAME, help = "bot name")]
     pub name: String,
+    #[arg(
+        long,
+        default_value = DEFAULT_SEED_PROMPT,
+        help = "instruction prompt; {name}, {peer_name} and {topic} are substituted"
+    )]
+    pub seed: Option<String>,
+    #[arg(short, long, default_value = DEFAULT_VOICE_ID, help = "PlayHT voice id")]
+    pub voice_id: String,
+    #[arg(
+        long,
+        help = "who the bot talks to, substituted for {peer_name} in the prompts [default: the other bots]"
+    )]
+    pub peer_name: Option<String>,
+    #[arg(
+        long,
+        help = "what the conversation is about, substituted for {topic} in the prompts"
+    )]
+    pub topic: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Jet {
+    #[arg(long, env = "NATS_URL", default_value = NATS_DEFAULT_URL, help = "NATS server URL")]
+    pub nats_url: String,
+    #[arg(long, help = "NATS user; requires the password")]
+    pub nats_user: Option<String>,
+    #[arg(long, hide_env_values = true, help = "NATS password")]
+    pub nats_password: Option<String>,
+    #[arg(long, hide_env_values = true, help = "NATS authentication token")]
+    pub nats_token: Option<String>,
+    #[arg(long, hide_env_values = true, help = "NATS NKey seed")]
+    pub nats_nkey: Option<String>,
+    #[arg(long, value_name = "FILE", help = "NATS credentials file")]
+    pub nats_creds: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "CA certificate the NATS server certificate is verified with"
+    )]
+    pub nats_tls_ca: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "NATS TLS client certificate; requires the key"
+    )]
+    pub nats_tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "NATS TLS client certificate key")]
+    pub nats_tls_key: Option<PathBuf>,
+    #[arg(long, help = "require TLS for the NATS connection")]
+    pub nats_require_tls: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = NATS_CONNECT_TIMEOUT,
+        help = "max time connecting to the NATS server may take"
+    )]
+    pub nats_connect_timeout: u64,
+    #[arg(
+        long,
+        help = "max number of reconnects in a row before the NATS connection is given up on [default: no limit]"
+    )]
+    pub nats_max_reconnects: Option<usize>,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = NATS_RECONNECT_BACKOFF,
+        help = "backoff before reconnecting to the NATS server; it doubles with every reconnect in a row"
+    )]
+    pub nats_reconnect_backoff: u64,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = NATS_MAX_RECONNECT_BACKOFF,
+        help = "max backoff before reconnecting to the NATS server"
+    )]
+    pub nats_max_reconnect_backoff: u64,
     #[arg(short, long, default_value = STREAM_NAME, help = "jetstram name")]
     pub stream_name: String,
     #[arg(short, long, default_value = BOT_PUB_SUBJECT, help = "jetstream publish subject")]
     pub pub_subject: String,
     #[arg(short = 'b', long, default_value = BOT_SUB_SUBJECT, help = "jetstream subscribe subject")]
     pub sub_subject: String,
+    #[arg(long, value_enum, default_value_t = jet::Backend::JetStream, help = "message transport")]
+    pub transport: jet::Backend,
+    #[arg(long, help = "conversation id; generated when not given")]
+    pub conversation_id: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        help = "format of the published messages [default: text over JetStream as gobot only understands raw text, json at the round table and over the local transport]"
+    )]
+    pub wire_format: Option<envelope::WireFormat>,
+}
+
+#[derive(Args, Debug)]
+pub struct LLM {
+    #[arg(
+        short = 't',
+        long,
+        help = "chat history token budget [default: derived from the model]"
+    )]
+    pub hist_tokens: Option<usize>,
+    #[arg(long, help = "summarize the messages evicted from chat history")]
+    pub summarize: bool,
+    #[arg(short, long, default_value = DEFAULT_MODEL_NAME, help = "LLM model")]
+    pub model_name: String,
+    #[arg(
+        long,
+        help = "sampling temperature; the higher, the more creative the replies"
+    )]
+    pub temperature: Option<f32>,
+    #[arg(long, help = "nucleus sampling probability mass")]
+    pub top_p: Option<f32>,
+    #[arg(long, help = "number of the most likely tokens sampled from")]
+    pub top_k: Option<u32>,
+    #[arg(
+        long,
+        help = "min probability of the sampled tokens relative to the most likely one"
+    )]
+    pub min_p: Option<f32>,
+    #[arg(long, help = "penalty for repeating the recent tokens")]
+    pub repeat_penalty: Option<f32>,
+    #[arg(
+        long,
+        value_name = "TOKENS",
+        help = "size of the model context; also bounds the chat history token budget"
+    )]
+    pub num_ctx: Option<u64>,
+    #[arg(
+        long,
+        value_name = "TOKENS",
+        help = "max number of tokens in the reply; the reply is cut off once it's reached"
+    )]
+    pub num_predict: Option<i32>,
+    #[arg(
+        long,
+        value_name = "SEQUENCE",
+        help = "sequence which ends the reply once it's generated; can be given multiple times"
+    )]
+    pub stop_sequence: Vec<String>,
+    #[arg(
+        long,
+        help = "seed of the LLM random number generator; makes the replies reproducible"
+    )]
+    pub llm_seed: Option<i32>,
+    #[arg(
+        long,
+        value_parser = validate_keep_alive,
+        help = "how long the model stays loaded after the request, e.g. 30s, 5m or 1h; 0 unloads it, -1 keeps it loaded"
+    )]
+    pub keep_alive: Option<String>,
+    #[arg(long, value_enum, default_value_t = llm::Backend::Ollama, help = "LLM backend")]
+    pub llm_backend: llm::Backend,
+    #[arg(
+        long,
+        env = "OLLAMA_HOST",
+        default_value = OLLAMA_DEFAULT_URL,
+        value_parser = llm::parse_ollama_url,
+        help = "Ollama server URL; the scheme and the port may be left out"
+    )]
+    pub ollama_url: String,
+    #[arg(
+        long,
+        hide_env_values = true,
+        help = "bearer token sent along with the Ollama requests"
+    )]
+    pub ollama_token: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME:VALUE",
+        hide_env_values = true,
+        value_parser = llm::parse_header,
+        help = "header sent along with the Ollama requests, e.g. to get through a proxy; can be given multiple times"
+    )]
+    pub ollama_header: Vec<(String, String)>,
+    #[arg(
+        long,
+        help = "pull the LLM models on startup if the Ollama server doesn't have them"
+    )]
+    pub pull_model: bool,
+    #[arg(long, help = "scripted LLM backend replies file, one reply per line")]
+    pub script: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = LLM_RETRIES,
+        help = "number of times the failed LLM request is retried"
+    )]
+    pub llm_retries: u32,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = LLM_RETRY_BACKOFF,
+        help = "backoff before the failed LLM request is retried; it doubles with every retry"
+    )]
+    pub llm_retry_backoff: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = LLM_TIMEOUT,
+        help = "max time the LLM request may take; 0 disables it"
+    )]
+    pub llm_timeout: u64,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "models tried in order once the LLM model keeps failing"
+    )]
+    pub fallback_model: Vec<String>,
+    #[arg(
+        long,
+        default_value = CANNED_REPLY,
+        help = "reply given once all the LLM models have failed"
+    )]
+    pub canned_reply: String,
+    #[arg(
+        long,
+        help = "stop the LLM stream instead of giving the canned reply once all the LLM models have failed"
+    )]
+    pub no_canned_reply: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Bot {
+    #[arg(
+        long,
+        help = "prompt which kicks off the local transport conversation [default: greets the first speaker]"
+    )]
+    pub kickoff: Option<String>,
+    #[arg(
+        long,
+        help = "stop speaking when another message arrives; press Enter to interrupt the bot"
+    )]
+    pub barge_in: bool,
+    #[arg(
+        long,
+        help = "start the conversation with the seed prompt instead of waiting to be prompted"
+    )]
+    pub start: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = TURN_TIMEOUT,
+        help = "max time the bot may hold the floor before its reply is cut short; 0 disables it"
+    )]
+    pub turn_timeout: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "take the floor when nobody has spoken for this long"
+    )]
+    pub kickstart_after: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = GRACE_PERIOD,
+        help = "time the bot has to wrap up the conversation when shutting down"
+    )]
+    pub grace_period: u64,
+    #[arg(
+        long,
+        help = "discard the reply cut short by the shutdown instead of publishing what's been said"
+    )]
+    pub discard_partial: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Table {
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "comma separated names of the round table participants in the order they speak; enables the round table"
+    )]
+    pub participants: Vec<String>,
+    #[arg(long, value_enum, default_value_t = turn::Policy::RoundRobin, help = "round table turn-taking policy")]
+    pub turn_policy: turn::Policy,
+    #[arg(
+        long,
+        help = "round table moderator with the moderator policy [default: the first participant]"
+    )]
+    pub moderator: Option<String>,
 }
 
 #[derive(Args, Debug)]
 pub struct TTS {
-    #[arg(short, default_value = DEFAULT_VOICE_ID, help = "bot name")]
-    pub voice_id: String,
+    #[arg(long, value_enum, default_value_t = tts::Backend::PlayHT, help = "TTS backend")]
+    pub tts_backend: tts::Backend,
+    #[arg(long, value_enum, default_value_t = tts::Quality::Low, help = "quality of the synthesized speech")]
+    pub tts_quality: tts::Quality,
+    #[arg(long, default_value_t = 1.0, help = "speed of the synthesized speech")]
+    pub tts_speed: f32,
+    #[arg(
+        long,
+        value_name = "HZ",
+        default_value_t = TTS_SAMPLE_RATE,
+        value_parser = clap::value_parser!(i32).range(MIN_TTS_SAMPLE_RATE..=MAX_TTS_SAMPLE_RATE),
+        help = "sample rate of the synthesized speech"
+    )]
+    pub tts_sample_rate: i32,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = MAX_TTS_BUFFER_SIZE,
+        help = "max size of the text sent to the speech synthesis at once"
+    )]
+    pub tts_buffer_size: usize,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = TTS_MAX_LATENCY,
+        help = "max time the text may wait for its sentence to finish before it's spoken"
+    )]
+    pub tts_max_latency: u64,
+    #[arg(
+        long,
+        default_value_t = TTS_RETRIES,
+        help = "number of times the failed speech synthesis request is retried"
+    )]
+    pub tts_retries: u32,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = TTS_RETRY_BACKOFF,
+        help = "backoff before the failed speech synthesis request is retried; it doubles with every retry"
+    )]
+    pub tts_retry_backoff: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = TTS_TIMEOUT,
+        help = "max time the speech synthesis request may take; 0 disables it"
+    )]
+    pub tts_timeout: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct Audio {
+    #[arg(
+        long,
+        visible_alias = "no-audio",
+        help = "run without speech synthesis and audio output"
+    )]
+    pub text_only: bool,
+    #[arg(long, value_enum, default_value_t = output::Backend::Rodio, help = "audio output")]
+    pub audio_output: output::Backend,
+    #[arg(
+        long,
+        help = "WAV file written by the file audio output [default: <bot-name>.wav]"
+    )]
+    pub audio_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = AUDIO_INTERVAL,
+        help = "how often the audio player checks whether the reply has been played out"
+    )]
+    pub audio_interval: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct Stop {
+    #[arg(long, help = "end the conversation after this many turns")]
+    pub max_turns: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "end the conversation after this many seconds"
+    )]
+    pub max_duration: Option<u64>,
+    #[arg(
+        long,
+        help = "end the conversation once this many tokens have been spoken"
+    )]
+    pub max_tokens: Option<usize>,
+    #[arg(
+        long,
+        help = "end the conversation once somebody says goodbye; the LLM is asked to say it when the conversation has run its course"
+    )]
+    pub goodbye: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Supervisor {
+    #[arg(
+        long,
+        value_name = "TASK=POLICY",
+        value_delimiter = ',',
+        value_parser = supervisor::parse_policy,
+        help = "what to do when the task fails: restart, degrade or abort; tasks: reader, writer, llm, tts, audio, transcript"
+    )]
+    pub restart_policy: Vec<(supervisor::Task, supervisor::Policy)>,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = RESTART_BACKOFF,
+        help = "backoff before the failed task is restarted; it doubles with every failure in a row"
+    )]
+    pub restart_backoff: u64,
+    #[arg(
+        long,
+        default_value_t = MAX_RESTARTS,
+        help = "max number of restarts in a row before the failed task is given up on"
+    )]
+    pub max_restarts: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct Transcript {
+    #[arg(
+        long,
+        help = "JSONL file to record the conversation transcript to; exported to Markdown alongside"
+    )]
+    pub transcript: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "JSONL transcript to resume the conversation from; recording continues into it"
+    )]
+    pub resume: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct Recording {
+    #[arg(
+        long,
+        help = "directory to record the synthesized speech of every turn to"
+    )]
+    pub record_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "record_dir",
+        help = "also stitch all the turns into a single recording"
+    )]
+    pub record_stitch: bool,
+}
+
+/// Validates the keep alive duration, keeping it as given.
+fn validate_keep_alive(s: &str) -> std::result::Result<String, String> {
+    llm::parse_keep_alive(s).map(|_| s.to_string())
 }
@@ -0,0 +1,241 @@
+use crate::{cli, persona, prelude::*};
+use clap::{
+    builder::ValueParser, error::ErrorKind, value_parser, Arg, ArgAction, ArgMatches, Command,
+    CommandFactory, FromArgMatches,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{collections::HashSet, env, fs, path::Path};
+
+/// Parses the flags layered over the environment, the config file and the persona: every flag
+/// can be set by the RUSTBOT_<FLAG> environment variable and by the config file, the flags taking
+/// precedence over the environment, the environment over the file and the file over the persona.
+pub fn parse() -> (cli::App, ArgMatches, Option<persona::Persona>) {
+    let mut cmd = cli::App::command().mut_args(|a| match a.get_env() {
+        Some(_) => a,
+        None => {
+            let flag = a.get_long().unwrap_or(a.get_id().as_str());
+            let name = format!("RUSTBOT_{}", flag.replace('-', "_").to_uppercase());
+            a.env(name)
+        }
+    });
+    let mut set = HashSet::new();
+    if let Some(path) = path() {
+        cmd = match load(cmd.clone(), &path, &mut set) {
+            Ok(cmd) => cmd,
+            Err(e) => cmd.error(ErrorKind::InvalidValue, e).exit(),
+        };
+    }
+    // NOTE: the persona may be chosen by any of the layers, so we need to parse them first.
+    let pre = cmd.clone().ignore_errors(true).get_matches();
+    let persona = match pre.get_one::<String>("profile") {
+        Some(name) => {
+            let dir = pre.get_one::<std::path::PathBuf>("persona_dir").cloned();
+            match persona::load(&dir.unwrap_or_default(), name) {
+                Ok(p) => Some(p),
+                Err(e) => cmd.error(ErrorKind::InvalidValue, e).exit(),
+            }
+        }
+        None => None,
+    };
+    if let Some(p) = &persona {
+        for (id, v) in p.flags() {
+            if !set.contains(id) {
+                cmd = cmd.mut_arg(id, |a| a.default_value(v));
+            }
+        }
+    }
+    let matches = cmd.get_matches_mut();
+    match cli::App::from_arg_matches(&matches) {
+        Ok(app) => (app, matches, persona),
+        Err(e) => e.format(&mut cmd).exit(),
+    }
+}
+
+/// Reads the TOML file.
+pub fn read<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let data = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(toml::from_str(&data).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+/// Returns the config file given by the --config flag or the environment.
+// NOTE: the file must be loaded before the flags are parsed as it sets their defaults.
+fn path() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    env::var("RUSTBOT_CONFIG").ok()
+}
+
+/// Loads the config file into the defaults of the flags and notes down which flags it sets.
+fn load(mut cmd: Command, path: &str, set: &mut HashSet<String>) -> Result<Command> {
+    let file: Value = read(Path::new(path))?;
+    let Value::Object(sections) = file else {
+        return Err(format!("{}: expected sections of settings", path).into());
+    };
+
+    for (section, settings) in sections {
+        let ids =
+            args(&cmd, &section).ok_or_else(|| format!("{}: unknown section {}", path, section))?;
+        let Value::Object(settings) = settings else {
+            return Err(format!("{}: {}: expected settings", path, section).into());
+        };
+        for (key, value) in settings {
+            let at = format!("{}.{}", section, key);
+            let arg = ids
+                .iter()
+                .map(|id| self::arg(&cmd, id))
+                .find(|a| self::key(&section, a) == key)
+                .ok_or_else(|| format!("{}: unknown key {}", path, at))?;
+            let id = arg.get_id().to_string();
+            let values =
+                values(&value).ok_or_else(|| format!("{}: {}: expected a value", path, at))?;
+            if values.len() != 1 && !matches!(arg.get_action(), ArgAction::Append) {
+                return Err(format!("{}: {}: expected a single value", path, at).into());
+            }
+            for v in &values {
+                validate(arg, v).map_err(|e| format!("{}: {}: {}", path, at, e))?;
+            }
+            set.insert(id.clone());
+            cmd = cmd.mut_arg(&id, |a| a.default_values(values));
+        }
+    }
+    Ok(cmd)
+}
+
+/// Validates the value with the value parser of the flag.
+// NOTE: clap doesn't validate the defaults, so the values are parsed as the only argument of a scratch command.
+fn validate(arg: &Arg, v: &str) -> Result<()> {
+    let check = Command::new("check").no_binary_name(true).arg(
+        Arg::new("value")
+            .allow_hyphen_values(true)
+            .value_parser(arg.get_value_parser().clone()),
+    );
+    let Err(e) = check.try_get_matches_from([v]) else {
+        return Ok(());
+    };
+    let mut msg = format!("invalid value {:?}", v);
+    if let Some(source) = std::error::Error::source(&e) {
+        msg.push_str(&format!(": {}", source));
+    }
+    let possible: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .map(|p| p.get_name().to_string())
+        .collect();
+    if !possible.is_empty() {
+        msg.push_str(&format!(", expected one of: {}", possible.join(", ")));
+    }
+    Err(msg.into())
+}
+
+/// Returns the ids of the flags in the section of the config file.
+fn args(cmd: &Command, section: &str) -> Option<Vec<String>> {
+    cmd.get_groups()
+        .find(|g| g.get_id().as_str().to_lowercase() == section)
+        .map(|g| {
+            g.get_args()
+                .map(|id| id.to_string())
+                .filter(|id| id != "config")
+                .collect::<Vec<_>>()
+        })
+        .filter(|ids| !ids.is_empty())
+}
+
+/// Returns the flag with the given id.
+fn arg<'a>(cmd: &'a Command, id: &str) -> &'a Arg {
+    cmd.get_arguments().find(|a| a.get_id() == id).unwrap()
+}
+
+/// Returns the config file key of the flag: the flag name in snake case;
+/// the flags named after their section drop the section name.
+fn key(section: &str, arg: &Arg) -> String {
+    let flag = arg
+        .get_long()
+        .unwrap_or(arg.get_id().as_str())
+        .replace('-', "_");
+    match flag.strip_prefix(section).and_then(|k| k.strip_prefix('_')) {
+        Some(key) => key.to_string(),
+        None => flag,
+    }
+}
+
+fn values(v: &Value) -> Option<Vec<String>> {
+    match v {
+        Value::String(s) => Some(vec![s.clone()]),
+        Value::Number(n) => Some(vec![n.to_string()]),
+        Value::Bool(b) => Some(vec![b.to_string()]),
+        Value::Array(a) => a.iter().map(|v| values(v)?.pop()).collect(),
+        _ => None,
+    }
+}
+
+/// Returns the effective config as a TOML config file.
+pub fn dump(matches: &ArgMatches) -> String {
+    let cmd = cli::App::command();
+    let mut out = String::new();
+    for group in cmd.get_groups() {
+        let section = group.get_id().as_str().to_lowercase();
+        let Some(ids) = args(&cmd, &section) else {
+            continue;
+        };
+        out.push_str(&format!("[{}]\n", section));
+        for id in &ids {
+            let arg = self::arg(&cmd, id);
+            // NOTE: the settings which aren't set have no TOML representation.
+            let Some(raw) = matches.get_raw(id) else {
+                continue;
+            };
+            if arg.is_hide_env_values_set() {
+                out.push_str(&format!("# {} = (redacted)\n", key(&section, arg)));
+                continue;
+            }
+            let values: Vec<toml::Value> = raw.map(|v| value(arg, &v.to_string_lossy())).collect();
+            let value = match arg.get_action() {
+                ArgAction::Append => toml::Value::Array(values),
+                _ => values
+                    .into_iter()
+                    .next()
+                    .unwrap_or(toml::Value::Array(Vec::new())),
+            };
+            out.push_str(&format!("{} = {}\n", key(&section, arg), value));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Returns the TOML value of the flag value, typed after the flag.
+fn value(arg: &Arg, v: &str) -> toml::Value {
+    let parser = arg.get_value_parser();
+    let is = |p: ValueParser| parser.type_id() == p.type_id();
+    if is(value_parser!(bool)) {
+        if let Ok(b) = v.parse() {
+            return toml::Value::Boolean(b);
+        }
+    }
+    if is(value_parser!(u64).into())
+        || is(value_parser!(u32).into())
+        || is(value_parser!(usize).into())
+        || is(value_parser!(i32).into())
+    {
+        if let Ok(n) = v.parse() {
+            return toml::Value::Integer(n);
+        }
+    }
+    if is(value_parser!(f32).into()) {
+        if let Ok(n) = v.parse() {
+            return toml::Value::Float(n);
+        }
+    }
+    toml::Value::String(v.to_string())
+}
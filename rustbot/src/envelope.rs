@@ -0,0 +1,228 @@
+use crate::prelude::*;
+use async_nats::HeaderMap;
+use bytes::Bytes;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WireFormat {
+    /// Versioned JSON envelope carrying the conversation metadata.
+    Json,
+    /// Raw reply text as understood by the gobot; the metadata is sent in the message headers.
+    Text,
+}
+
+/// Message is what's sent over the wire: the payload and its headers.
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    pub headers: HeaderMap,
+    pub payload: Bytes,
+}
+
+impl From<Bytes> for Message {
+    fn from(payload: Bytes) -> Self {
+        Message {
+            payload,
+            ..Default::default()
+        }
+    }
+}
+
+/// Envelope wraps a single conversation turn sent between the bots.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    pub conversation_id: String,
+    pub sender: String,
+    pub turn: u64,
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub text: String,
+    /// The participant who speaks next at the round table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    /// Marks the end of the conversation.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub end: bool,
+}
+
+impl Envelope {
+    pub fn new(text: String) -> Self {
+        Envelope {
+            version: ENVELOPE_VERSION,
+            timestamp: now(),
+            text,
+            ..Default::default()
+        }
+    }
+
+    /// Decodes the envelope from the message.
+    /// Payloads which are not JSON envelopes are treated as raw text; unless
+    /// its headers carry the metadata, it's decoded into an envelope with version 0.
+    pub fn decode(m: &Message) -> Result<Self> {
+        if let Ok(e) = serde_json::from_slice::<Envelope>(&m.payload) {
+            if e.version > 0 {
+                return Ok(e);
+            }
+        }
+        let text = String::from_utf8(m.payload.to_vec())?;
+        let header = |name: &str| m.headers.get(name).map(|v| v.as_str().to_string());
+        let Some(version) = header(HEADER_VERSION) else {
+            return Ok(Envelope {
+                version: 0,
+                timestamp: now(),
+                text,
+                ..Default::default()
+            });
+        };
+        Ok(Envelope {
+            version: version.parse()?,
+            conversation_id: header(HEADER_CONVERSATION_ID).unwrap_or_default(),
+            sender: header(HEADER_SENDER).unwrap_or_default(),
+            turn: header(HEADER_TURN)
+                .map(|t| t.parse())
+                .transpose()?
+                .unwrap_or_default(),
+            timestamp: match header(HEADER_TIMESTAMP) {
+                Some(t) => t.parse()?,
+                None => now(),
+            },
+            model: header(HEADER_MODEL),
+            text,
+            next: header(HEADER_NEXT),
+            end: header(HEADER_END).is_some_and(|e| e == "true"),
+        })
+    }
+
+    pub fn encode(&self, format: WireFormat) -> Result<Message> {
+        match format {
+            WireFormat::Json => Ok(Bytes::from(serde_json::to_vec(self)?).into()),
+            WireFormat::Text => {
+                let mut headers = HeaderMap::new();
+                headers.insert(HEADER_VERSION, self.version.to_string().as_str());
+                headers.insert(HEADER_CONVERSATION_ID, self.conversation_id.as_str());
+                headers.insert(HEADER_SENDER, self.sender.as_str());
+                headers.insert(HEADER_TURN, self.turn.to_string().as_str());
+                headers.insert(HEADER_TIMESTAMP, self.timestamp.to_string().as_str());
+                if let Some(model) = &self.model {
+                    headers.insert(HEADER_MODEL, model.as_str());
+                }
+                if let Some(next) = &self.next {
+                    headers.insert(HEADER_NEXT, next.as_str());
+                }
+                if self.end {
+                    headers.insert(HEADER_END, "true");
+                }
+                Ok(Message {
+                    headers,
+                    payload: Bytes::from(self.text.clone()),
+                })
+            }
+        }
+    }
+}
+
+/// Conversation keeps track of the conversation the bot takes part in.
+#[derive(Clone, Debug)]
+pub struct Conversation {
+    pub id: String,
+    pub turn: u64,
+}
+
+impl Conversation {
+    pub fn new(id: Option<String>, turn: u64) -> Self {
+        Conversation {
+            id: id.unwrap_or_else(|| format!("{:016x}", rand::random::<u64>())),
+            turn,
+        }
+    }
+
+    /// Fills in the metadata missing from raw text envelopes.
+    pub fn fill(&self, e: &mut Envelope) {
+        if e.version == 0 {
+            e.conversation_id = self.id.clone();
+            e.turn = self.turn + 1;
+        }
+    }
+
+    /// Updates the conversation with the received envelope; the envelopes
+    /// dropped by the turn coordinator must not update it.
+    pub fn receive(&mut self, e: &Envelope) {
+        // NOTE: we join the conversation of whoever spoke to us.
+        self.id = e.conversation_id.clone();
+        self.turn = self.turn.max(e.turn);
+    }
+
+    /// Returns the envelope of the next turn taken by the sender.
+    pub fn next(&mut self, sender: &str, model: Option<String>, text: String) -> Envelope {
+        self.turn += 1;
+        Envelope {
+            conversation_id: self.id.clone(),
+            sender: sender.to_string(),
+            turn: self.turn,
+            model,
+            ..Envelope::new(text)
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        let mut conv = Conversation::new(Some("c0ffee".into()), 6);
+        let mut e = conv.next("rustbot", Some("llama3".into()), "Hi gobot!".into());
+        e.next = Some("gobot".into());
+        e
+    }
+
+    #[test]
+    fn sends_the_metadata_along_with_the_raw_text() {
+        let e = envelope();
+        let m = e.encode(WireFormat::Text).unwrap();
+        assert_eq!(m.payload, "Hi gobot!");
+
+        let d = Envelope::decode(&m).unwrap();
+        assert_eq!(d.version, ENVELOPE_VERSION);
+        assert_eq!(d.conversation_id, "c0ffee");
+        assert_eq!(d.sender, "rustbot");
+        assert_eq!(d.turn, 7);
+        assert_eq!(d.timestamp, e.timestamp);
+        assert_eq!(d.model.as_deref(), Some("llama3"));
+        assert_eq!(d.next.as_deref(), Some("gobot"));
+        assert_eq!(d.text, "Hi gobot!");
+        assert!(!d.end);
+    }
+
+    #[test]
+    fn marks_the_end_in_both_wire_formats() {
+        let mut e = envelope();
+        e.end = true;
+        for format in [WireFormat::Json, WireFormat::Text] {
+            assert!(Envelope::decode(&e.encode(format).unwrap()).unwrap().end);
+        }
+    }
+
+    #[test]
+    fn decodes_both_wire_formats() {
+        let d = Envelope::decode(&envelope().encode(WireFormat::Json).unwrap()).unwrap();
+        assert_eq!(
+            (d.version, d.turn, d.text.as_str()),
+            (ENVELOPE_VERSION, 7, "Hi gobot!")
+        );
+
+        let d = Envelope::decode(&Bytes::from("Hi rustbot!").into()).unwrap();
+        assert_eq!((d.version, d.turn, d.text.as_str()), (0, 0, "Hi rustbot!"));
+    }
+}
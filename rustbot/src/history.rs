@@ -1,44 +1,137 @@
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::fmt;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::System => write!(f, "system"),
+            Role::User => write!(f, "user"),
+            Role::Assistant => write!(f, "assistant"),
+        }
+    }
+}
+
+/// Message is a single role-tagged chat history entry.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn system(content: String) -> Self {
+        Message {
+            role: Role::System,
+            content,
+        }
+    }
+
+    pub fn user(content: String) -> Self {
+        Message {
+            role: Role::User,
+            content,
+        }
+    }
+
+    pub fn assistant(content: String) -> Self {
+        Message {
+            role: Role::Assistant,
+            content,
+        }
+    }
+}
+
+/// Returns a rough estimate of the number of tokens the text takes up.
+/// NOTE: most LLM tokenizers average about four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4) + MESSAGE_TOKEN_OVERHEAD
+}
+
+/// History is a chat history bounded by an approximate token budget.
+/// Pinned messages, such as the seed prompt, and the summary of the
+/// evicted messages are never evicted.
 #[derive(Clone, Debug)]
 pub struct History {
-    data: VecDeque<String>,
-    size: usize,
+    pinned: Vec<Message>,
+    summary: Option<Message>,
+    data: VecDeque<Message>,
+    budget: usize,
 }
 
 impl History {
-    pub fn new(size: usize) -> Self {
+    pub fn new(budget: usize) -> Self {
         History {
-            data: VecDeque::with_capacity(size),
-            size,
+            pinned: Vec::new(),
+            summary: None,
+            data: VecDeque::new(),
+            budget,
         }
     }
 
-    pub fn add(&mut self, element: String) {
-        if self.data.len() == self.size {
-            self.data.pop_front();
-        }
+    pub fn pin(&mut self, element: Message) {
+        self.pinned.push(element);
+    }
+
+    pub fn summary(&self) -> Option<&Message> {
+        self.summary.as_ref()
+    }
+
+    pub fn set_summary(&mut self, summary: Message) {
+        self.summary = Some(summary);
+    }
+
+    /// Adds the element to history and returns the messages
+    /// which had to be evicted to keep history within its budget.
+    pub fn add(&mut self, element: Message) -> Vec<Message> {
         self.data.push_back(element);
+        self.evict()
+    }
+
+    fn tokens(&self) -> usize {
+        self.iter().map(|m| estimate_tokens(&m.content)).sum()
+    }
+
+    pub fn messages(&self) -> Vec<Message> {
+        self.iter().cloned().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Message> {
+        self.pinned
+            .iter()
+            .chain(self.summary.iter())
+            .chain(self.data.iter())
     }
 
-    pub fn string(&self) -> String {
-        let mut s = String::new();
-        for string in &self.data {
-            s.push_str(string);
-            s.push('\n');
+    // NOTE: we always keep the latest message even if it doesn't fit the budget.
+    fn evict(&mut self) -> Vec<Message> {
+        let mut evicted = Vec::new();
+        let mut tokens = self.tokens();
+        while tokens > self.budget && self.data.len() > 1 {
+            if let Some(m) = self.data.pop_front() {
+                tokens -= estimate_tokens(&m.content);
+                evicted.push(m);
+            }
         }
-        s.pop(); // Remove the last newline character
-        s
+        evicted
     }
 }
 
 impl fmt::Display for History {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut result = Vec::with_capacity(self.size);
-        for string in &self.data {
-            result.push(string.as_str());
-        }
+        let result: Vec<String> = self
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content))
+            .collect();
         write!(f, "{}", result.join("\n"))
     }
 }
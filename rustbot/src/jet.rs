@@ -1,103 +1,511 @@
-use crate::prelude::*;
-use async_nats::jetstream::{
-    self,
-    consumer::{pull, Consumer},
-    stream,
+use crate::{
+    bot::Interrupt,
+    envelope::{Conversation, Envelope, Message, WireFormat},
+    llm::Chunk,
+    prelude::*,
+    stop,
+    turn::{self, Expiry, Verdict},
+};
+use async_nats::{
+    jetstream::{self, consumer::pull, stream},
+    ConnectOptions, Event,
+};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use clap::ValueEnum;
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
 };
-use bytes::{Bytes, BytesMut};
 use tokio::{
     self,
-    sync::mpsc::{Receiver, Sender},
-    sync::watch,
+    sync::mpsc::{self, Receiver, Sender},
+    sync::{watch, Notify},
+    time::{self, Duration, Instant},
 };
-use tokio_stream::StreamExt;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
+
+/// A stream of messages received on a subject.
+pub type Messages = Pin<Box<dyn tokio_stream::Stream<Item = Result<Message>> + Send>>;
+
+/// Transport moves messages between bots.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn publish(&self, subject: String, message: Message) -> Result<()>;
+    async fn subscribe(&self, subject: String) -> Result<Messages>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Exchange messages through NATS JetStream.
+    #[value(name = "jetstream")]
+    JetStream,
+    /// Exchange messages with an in-process peer bot; no NATS server required.
+    Local,
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub nats_url: String,
+    pub nats: Nats,
     pub durable_name: String,
     pub stream_name: String,
     pub pub_subject: String,
     pub sub_subject: String,
+    pub conversation_id: Option<String>,
+    /// The last turn taken in the conversation, e.g. when resuming it.
+    pub turn: u64,
+    pub model_name: Option<String>,
+    pub wire_format: WireFormat,
+    /// Discard the reply cut short by the shutdown instead of publishing it.
+    pub discard_partial: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             nats_url: std::env::var("NATS_URL").unwrap_or_else(|_| NATS_DEFAULT_URL.to_string()),
+            nats: Nats::default(),
             durable_name: BOT_NAME.to_string(),
             stream_name: STREAM_NAME.to_string(),
             pub_subject: BOT_PUB_SUBJECT.to_string(),
             sub_subject: BOT_SUB_SUBJECT.to_string(),
+            conversation_id: None,
+            turn: 0,
+            model_name: None,
+            wire_format: WireFormat::Json,
+            discard_partial: false,
         }
     }
 }
 
-pub struct Stream {
-    pub writer: Writer,
-    pub reader: Reader,
+/// Nats configures the connection to the NATS server; at most one of
+/// the user and password, token, NKey and credentials file is used to authenticate.
+#[derive(Clone, Debug)]
+pub struct Nats {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    /// NKey seed.
+    pub nkey: Option<String>,
+    pub creds_file: Option<PathBuf>,
+    /// CA certificate the server certificate is verified with.
+    pub tls_ca: Option<PathBuf>,
+    /// Client certificate and its key.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub require_tls: bool,
+    pub connect_timeout: Duration,
+    /// Max number of reconnects before the connection is given up on; no limit when not set.
+    pub max_reconnects: Option<usize>,
+    /// Backoff before the first reconnect; it doubles with every reconnect in a row.
+    pub reconnect_backoff: Duration,
+    pub max_reconnect_backoff: Duration,
 }
 
-impl Stream {
-    pub async fn new(c: Config) -> Result<Self> {
-        let client = async_nats::connect(c.nats_url).await?;
+impl Default for Nats {
+    fn default() -> Self {
+        Nats {
+            user: None,
+            password: None,
+            token: None,
+            nkey: None,
+            creds_file: None,
+            tls_ca: None,
+            tls_cert: None,
+            tls_key: None,
+            require_tls: false,
+            connect_timeout: Duration::from_secs(NATS_CONNECT_TIMEOUT),
+            max_reconnects: None,
+            reconnect_backoff: Duration::from_millis(NATS_RECONNECT_BACKOFF),
+            max_reconnect_backoff: Duration::from_millis(NATS_MAX_RECONNECT_BACKOFF),
+        }
+    }
+}
+
+impl Nats {
+    /// Returns the NATS connect options; the connection events are logged.
+    pub async fn options(&self) -> Result<ConnectOptions> {
+        let auths = [
+            self.user.is_some() || self.password.is_some(),
+            self.token.is_some(),
+            self.nkey.is_some(),
+            self.creds_file.is_some(),
+        ];
+        if auths.iter().filter(|a| **a).count() > 1 {
+            return Err("only one of the NATS user and password, token, NKey and credentials file can be given".into());
+        }
+        let mut opts = ConnectOptions::new();
+        match (&self.user, &self.password) {
+            (Some(user), Some(password)) => {
+                opts = opts.user_and_password(user.clone(), password.clone())
+            }
+            (None, None) => {}
+            _ => return Err("the NATS user and password must be given together".into()),
+        }
+        if let Some(token) = &self.token {
+            opts = opts.token(token.clone());
+        }
+        if let Some(nkey) = &self.nkey {
+            opts = opts.nkey(nkey.clone());
+        }
+        if let Some(path) = &self.creds_file {
+            opts = opts.credentials_file(path).await.map_err(|e| {
+                format!("Failed to read NATS credentials {}: {}", path.display(), e)
+            })?;
+        }
+        if let Some(path) = &self.tls_ca {
+            opts = opts.add_root_certificates(path.clone());
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                opts = opts.add_client_certificate(cert.clone(), key.clone())
+            }
+            (None, None) => {}
+            _ => {
+                return Err("the NATS TLS client certificate and key must be given together".into())
+            }
+        }
+        let (backoff, max_backoff) = (self.reconnect_backoff, self.max_reconnect_backoff);
+        Ok(opts
+            .require_tls(self.require_tls)
+            .connection_timeout(self.connect_timeout)
+            .max_reconnects(self.max_reconnects)
+            .reconnect_delay_callback(move |attempts| {
+                // NOTE: the attempts are counted from the last connection, which is retried right away.
+                if attempts <= 1 {
+                    return Duration::ZERO;
+                }
+                let delay = backoff
+                    .saturating_mul(1 << (attempts - 2).min(16))
+                    .min(max_backoff);
+                eprintln!(
+                    "reconnecting to NATS in {}ms (attempt {})",
+                    delay.as_millis(),
+                    attempts
+                );
+                delay
+            })
+            .event_callback(|event| async move {
+                match event {
+                    Event::Connected => println!("connected to NATS"),
+                    Event::Disconnected => eprintln!("disconnected from NATS"),
+                    e => eprintln!("NATS: {}", e),
+                }
+            }))
+    }
+}
+
+pub struct JetStream {
+    js: jetstream::Context,
+    stream: stream::Stream,
+    durable_name: String,
+}
+
+impl JetStream {
+    pub async fn new(c: &Config) -> Result<Self> {
+        let client = c.nats.options().await?.connect(c.nats_url.clone()).await?;
         let js = jetstream::new(client);
 
+        // NOTE: the stream must not list subjects which overlap,
+        // e.g. the round table subscribe subject covers the publish one.
+        let mut subjects = vec![c.sub_subject.clone()];
+        if !subject_matches(&c.sub_subject, &c.pub_subject) {
+            subjects.push(c.pub_subject.clone());
+        }
         let stream = js
             .get_or_create_stream(stream::Config {
-                name: c.stream_name,
+                name: c.stream_name.clone(),
+                subjects,
                 ..Default::default()
             })
             .await?;
 
-        let cons = stream
+        Ok(JetStream {
+            js,
+            stream,
+            durable_name: c.durable_name.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for JetStream {
+    async fn publish(&self, subject: String, message: Message) -> Result<()> {
+        self.js
+            .publish_with_headers(subject, message.headers, message.payload)
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, subject: String) -> Result<Messages> {
+        let cons = self
+            .stream
             .create_consumer(pull::Config {
-                durable_name: Some(c.durable_name.clone()),
-                filter_subject: c.sub_subject.clone(),
+                durable_name: Some(self.durable_name.clone()),
+                filter_subject: subject,
                 ..Default::default()
             })
             .await?;
 
+        let messages = cons.messages().await?.then(|message| async move {
+            let message = message?;
+            message.ack().await?;
+            Ok(Message {
+                headers: message.headers.clone().unwrap_or_default(),
+                payload: message.payload.clone(),
+            })
+        });
+        Ok(Box::pin(messages))
+    }
+}
+
+/// Local is an in-process transport which routes messages over channels.
+/// Subscriptions may use the NATS subject wildcards. Messages published
+/// before anyone subscribes to their subject are buffered.
+#[derive(Clone, Default)]
+pub struct Local {
+    routes: Arc<Mutex<Routes>>,
+}
+
+#[derive(Default)]
+struct Routes {
+    subs: Vec<(String, mpsc::UnboundedSender<Message>)>,
+    pending: Vec<(String, Message)>,
+}
+
+#[async_trait]
+impl Transport for Local {
+    async fn publish(&self, subject: String, message: Message) -> Result<()> {
+        let mut routes = self.routes.lock().unwrap();
+        // NOTE: subscribers which have gone away are dropped.
+        routes.subs.retain(|(_, tx)| !tx.is_closed());
+        let mut delivered = false;
+        for (pattern, tx) in routes.subs.iter() {
+            if subject_matches(pattern, &subject) {
+                tx.send(message.clone())?;
+                delivered = true;
+            }
+        }
+        if !delivered {
+            routes.pending.push((subject, message));
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, subject: String) -> Result<Messages> {
+        let mut routes = self.routes.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (matched, pending) = std::mem::take(&mut routes.pending)
+            .into_iter()
+            .partition(|(s, _)| subject_matches(&subject, s));
+        routes.pending = pending;
+        for (_, message) in matched {
+            tx.send(message)?;
+        }
+        routes.subs.push((subject, tx));
+        Ok(Box::pin(UnboundedReceiverStream::new(rx).map(Ok)))
+    }
+}
+
+/// Returns true if the subject matches the pattern: the * wildcard
+/// matches a single token and the > wildcard all the remaining tokens.
+pub fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for p in pattern.split('.') {
+        match (p, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (p, Some(t)) if p == t => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+pub struct Stream {
+    pub writer: Writer,
+    pub reader: Reader,
+    pub conv: Arc<Mutex<Conversation>>,
+    pub budget: Arc<Mutex<stop::Budget>>,
+}
+
+impl Stream {
+    pub async fn new(
+        t: Arc<dyn Transport>,
+        c: Config,
+        coord: turn::Coordinator,
+        budget: stop::Budget,
+    ) -> Result<Self> {
+        let messages = t.subscribe(c.sub_subject.clone()).await?;
+        let conv = Arc::new(Mutex::new(Conversation::new(c.conversation_id, c.turn)));
+        let coord = Arc::new(Mutex::new(coord));
+        let changed = Arc::new(Notify::new());
+        let budget = Arc::new(Mutex::new(budget));
+        let ending = Arc::new(Notify::new());
+
         Ok(Stream {
             writer: Writer {
-                tx: js,
+                tx: t.clone(),
                 subject: c.pub_subject.clone(),
+                sender: c.durable_name.clone(),
+                model_name: c.model_name,
+                format: c.wire_format,
+                discard_partial: c.discard_partial,
+                conv: conv.clone(),
+                coord: coord.clone(),
+                changed: changed.clone(),
+                budget: budget.clone(),
+                ending: ending.clone(),
             },
             reader: Reader {
-                rx: cons,
+                tx: t,
+                rx: Some(messages),
+                name: c.durable_name.clone(),
                 subject: c.sub_subject.clone(),
+                conv: conv.clone(),
+                coord,
+                changed,
+                budget: budget.clone(),
+                ending,
             },
+            conv,
+            budget,
         })
     }
 }
 
-#[allow(unused)]
 pub struct Reader {
-    rx: Consumer<pull::Config>,
+    tx: Arc<dyn Transport>,
+    /// The subscription; it's renewed when the reader is restarted after it has failed.
+    rx: Option<Messages>,
+    name: String,
     subject: String,
+    conv: Arc<Mutex<Conversation>>,
+    coord: Arc<Mutex<turn::Coordinator>>,
+    /// Notified when the writer hands the floor over.
+    changed: Arc<Notify>,
+    budget: Arc<Mutex<stop::Budget>>,
+    /// Notifies the writer that the conversation has ended.
+    ending: Arc<Notify>,
 }
 
 impl Reader {
+    /// Reads the prompts sent to the bot and lets the turn coordinator decide
+    /// which of them the bot replies to. The prompts which beat the reply
+    /// the bot is giving cut it short. When the coordinator timeouts expire,
+    /// the reply is cut short or the bot is prompted to speak unprompted.
+    /// Once the conversation ends, the writer is left to wrap it up.
     pub async fn read(
-        self,
-        prompts: Sender<String>,
+        &mut self,
+        prompts: &Sender<Envelope>,
+        transcript: &Option<Sender<Envelope>>,
+        interrupt: &watch::Sender<Interrupt>,
         mut done: watch::Receiver<bool>,
     ) -> Result<()> {
         println!("launching JetStream Reader");
-        let mut messages = self.rx.messages().await?;
+        let mut rx = match self.rx.take() {
+            Some(rx) => rx,
+            None => {
+                println!("subscribing to {} again", self.subject);
+                self.tx.subscribe(self.subject.clone()).await?
+            }
+        };
 
+        let ends_at = self.budget.lock().unwrap().deadline();
+        let mut interrupts = interrupt.subscribe();
         loop {
+            let deadline = self.coord.lock().unwrap().deadline();
+            let ended = self.budget.lock().unwrap().ended().is_some();
             tokio::select! {
                 _ = done.changed() => {
                     if *done.borrow() {
                         return Ok(())
                     }
                 },
-                Some(Ok(message)) = messages.next() => {
-                    println!("\n[Q]: {:?}", message.payload.to_owned());
-                    message.ack().await?;
+                // NOTE: the deadline may have moved.
+                _ = self.changed.notified() => {},
+                _ = interrupts.changed(), if !ended => {
+                    if *interrupts.borrow_and_update() == Interrupt::Shutdown {
+                        self.budget.lock().unwrap().end(stop::Reason::Shutdown);
+                        self.ending.notify_one();
+                    }
+                },
+                _ = time::sleep_until(ends_at.unwrap_or_else(Instant::now)), if ends_at.is_some() && !ended => {
+                    println!("\n[time is up]");
+                    self.budget.lock().unwrap().end(stop::Reason::MaxDuration);
+                    self.ending.notify_one();
+                },
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() && !ended => {
+                    let expiry = self.coord.lock().unwrap().expire();
+                    match expiry {
+                        Some(Expiry::Turn) => {
+                            println!("\n[turn timeout]");
+                            // NOTE: what's been said so far is published.
+                            interrupt.send(Interrupt::Operator)?;
+                        }
+                        Some(Expiry::Kickstart) => {
+                            println!("\n[kick-start]");
+                            let prompt = self.coord.lock().unwrap().kickstart();
+                            prompts.send(prompt).await?;
+                        }
+                        None => {}
+                    }
+                },
+                next = rx.next() => {
+                    let message = match next {
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => return Err(format!("Failed to receive the message: {}", e).into()),
+                        None => return Err(format!("subscription to {} has ended", self.subject).into()),
+                    };
                     // NOTE: maybe we can send an empty string of the conversion fails?
-                    let prompt = String::from_utf8(message.payload.to_vec())?;
+                    let mut prompt = Envelope::decode(&message)?;
+                    // NOTE: at the round table we hear our own turns, too.
+                    if prompt.sender == self.name || ended {
+                        continue;
+                    }
+                    self.conv.lock().unwrap().fill(&mut prompt);
+                    if prompt.end {
+                        self.conv.lock().unwrap().receive(&prompt);
+                        println!("\n[end] #{} {}: {}", prompt.turn, prompt.sender, prompt.text);
+                        record(transcript, prompt.clone()).await;
+                        // NOTE: when shutting down the reply is seen through to the end anyway.
+                        let shutdown = *interrupt.borrow() == Interrupt::Shutdown;
+                        if !shutdown && self.coord.lock().unwrap().release() {
+                            interrupt.send(Interrupt::Message)?;
+                        }
+                        self.budget.lock().unwrap().end(stop::Reason::Ended(prompt.sender));
+                        self.ending.notify_one();
+                        continue;
+                    }
+                    let (held, verdict) = {
+                        let mut coord = self.coord.lock().unwrap();
+                        (coord.holds_floor(), coord.receive(&prompt))
+                    };
+                    if verdict == Verdict::Drop {
+                        println!("\n[dropped] #{} {}: {}", prompt.turn, prompt.sender, prompt.text);
+                        continue;
+                    }
+                    self.conv.lock().unwrap().receive(&prompt);
+                    println!("\n[Q] #{} {}: {}", prompt.turn, prompt.sender, prompt.text);
+                    record(transcript, prompt.clone()).await;
+                    let reason = self.budget.lock().unwrap().record(&prompt);
+                    if let Some(reason) = reason {
+                        // NOTE: nobody replies to the last turn.
+                        let held = self.coord.lock().unwrap().release();
+                        if held {
+                            interrupt.send(Interrupt::Message)?;
+                        }
+                        self.budget.lock().unwrap().end(reason);
+                        self.ending.notify_one();
+                        continue;
+                    }
+                    // NOTE: the interrupt must come before the prompt
+                    // so that it doesn't cut the reply to the prompt short.
+                    if held {
+                        interrupt.send(Interrupt::Message)?;
+                    }
                     prompts.send(prompt).await?;
                 }
             }
@@ -106,46 +514,403 @@ impl Reader {
 }
 
 pub struct Writer {
-    tx: jetstream::Context,
+    tx: Arc<dyn Transport>,
     subject: String,
+    sender: String,
+    model_name: Option<String>,
+    format: WireFormat,
+    discard_partial: bool,
+    conv: Arc<Mutex<Conversation>>,
+    coord: Arc<Mutex<turn::Coordinator>>,
+    changed: Arc<Notify>,
+    budget: Arc<Mutex<stop::Budget>>,
+    ending: Arc<Notify>,
 }
 
 impl Writer {
+    /// Publishes the replies assembled from the received chunks. When audio_done
+    /// is given, each reply is published only once its audio has finished playing.
+    /// Interrupted replies are only published when cut short by the operator.
+    /// Once the conversation ends and the last reply has been published,
+    /// the end of the conversation is announced and stop is signalled.
     pub async fn write(
-        self,
-        mut chunks: Receiver<Bytes>,
-        mut audio_done: watch::Receiver<bool>,
-        mut done: watch::Receiver<bool>,
+        &self,
+        chunks: &mut Receiver<Chunk>,
+        audio_done: &mut Option<watch::Receiver<bool>>,
+        transcript: &Option<Sender<Envelope>>,
+        mut interrupt: watch::Receiver<Interrupt>,
+        stop: &watch::Sender<bool>,
     ) -> Result<()> {
         println!("launching JetStream Writer");
+        let mut done = stop.subscribe();
         let mut b = BytesMut::new();
+        let mut started = false;
+        let mut next = None;
+        // NOTE: the conversation may have ended before the writer was restarted.
+        let mut ending = self.budget.lock().unwrap().ended().is_some();
         loop {
+            // NOTE: the reply the bot is giving is seen through to the end.
+            if ending && !self.coord.lock().unwrap().holds_floor() {
+                self.end(transcript).await?;
+                stop.send(true)?;
+                return Ok(());
+            }
             tokio::select! {
                 _ = done.changed() => {
                     if *done.borrow() {
                         return Ok(())
                     }
                 },
+                _ = self.ending.notified() => ending = true,
                 Some(chunk) = chunks.recv() => {
-                    if chunk.is_empty() {
-                        let msg = String::from_utf8(b.to_vec())?;
+                    if !started {
+                        // NOTE: nothing that happened before the reply started can interrupt it.
+                        interrupt.borrow_and_update();
+                        if let Some(audio_done) = audio_done.as_mut() {
+                            audio_done.borrow_and_update();
+                        }
+                        started = true;
+                    }
+                    let mut interrupted = match chunk {
+                        Chunk::Text(chunk) => {
+                            b.extend_from_slice(&chunk);
+                            continue;
+                        }
+                        Chunk::Next(name) => {
+                            next = Some(name);
+                            continue;
+                        }
+                        Chunk::Failed => {
+                            started = false;
+                            b.clear();
+                            next = None;
+                            println!("\n[failed]");
+                            self.coord.lock().unwrap().release();
+                            self.changed.notify_one();
+                            continue;
+                        }
+                        Chunk::End => false,
+                        Chunk::Interrupted => true,
+                    };
+                    started = false;
+                    let msg = String::from_utf8(b.split().to_vec())?;
+                    if interrupted {
+                        println!("\n[A] (interrupted): {}", msg);
+                    } else {
                         println!("\n[A]: {}", msg);
-                        loop {
+                    }
+
+                    let mut silenced = false;
+                    if let Some(audio_done) = audio_done.as_mut() {
+                        if !interrupted {
+                            interrupted = tokio::select! {
+                                biased;
+                                _ = interrupt.changed() => true,
+                                played = played(audio_done) => {
+                                    silenced = !played;
+                                    false
+                                }
+                            };
+                            if interrupted {
+                                println!("\n[interrupted]");
+                            }
+                        }
+                        // NOTE: on shutdown the sentence being spoken is played out.
+                        if interrupted && *interrupt.borrow() == Interrupt::Shutdown {
                             tokio::select! {
-                                _ = audio_done.changed() => {
-                                    if *audio_done.borrow() {
-                                        self.tx.publish(self.subject.to_string(), b.clone().freeze())
-                                            .await?;
-                                        b.clear();
-                                        break;
-                                    }
-                                },
+                                played = played(audio_done) => silenced = !played,
+                                _ = done.wait_for(|d| *d) => return Ok(()),
                             }
                         }
                     }
-                    b.extend_from_slice(&chunk);
+                    if silenced {
+                        if *done.borrow() {
+                            return Ok(());
+                        }
+                        println!("[audio player is gone, carrying on text-only]");
+                        *audio_done = None;
+                    }
+                    let next = next.take();
+                    if interrupted {
+                        let publish = match *interrupt.borrow() {
+                            // NOTE: when interrupted by a message the bot replies to it instead.
+                            Interrupt::None | Interrupt::Message => false,
+                            Interrupt::Operator => true,
+                            Interrupt::Shutdown => !self.discard_partial,
+                        };
+                        if !publish {
+                            if *interrupt.borrow() == Interrupt::Shutdown {
+                                println!("[discarded]");
+                                self.coord.lock().unwrap().release();
+                                self.changed.notify_one();
+                            }
+                            continue;
+                        }
+                    }
+                    // NOTE: e.g. the reply cut short before anything was generated;
+                    // there's nothing to reply to, so the floor is handed over.
+                    if msg.trim().is_empty() {
+                        println!("[nothing to publish]");
+                        self.coord.lock().unwrap().release();
+                        self.changed.notify_one();
+                        continue;
+                    }
+
+                    let mut reply = self.conv.lock().unwrap().next(&self.sender, self.model_name.clone(), msg);
+                    if let Some(next) = next {
+                        println!("[next speaker: {}]", next);
+                        reply.next = Some(next);
+                    }
+                    self.tx.publish(self.subject.to_string(), reply.encode(self.format)?).await?;
+                    self.coord.lock().unwrap().spoke(&reply);
+                    self.changed.notify_one();
+                    let reason = self.budget.lock().unwrap().record(&reply);
+                    if let Some(reason) = reason {
+                        self.budget.lock().unwrap().end(reason);
+                        ending = true;
+                    }
+                    record(transcript, reply).await;
                 }
             }
         }
     }
+
+    /// Announces the end of the conversation, unless somebody else already has.
+    /// The bot shutting down leaves the conversation to the others.
+    async fn end(&self, transcript: &Option<Sender<Envelope>>) -> Result<()> {
+        let reason = self.budget.lock().unwrap().ended().cloned();
+        let Some(reason) = reason else {
+            return Ok(());
+        };
+        println!("\n[end]: {}", reason);
+        if matches!(reason, stop::Reason::Ended(_) | stop::Reason::Shutdown) {
+            return Ok(());
+        }
+        let text = format!("{}: {}.", END_OF_CONVERSATION, reason);
+        let mut e = self
+            .conv
+            .lock()
+            .unwrap()
+            .next(&self.sender, self.model_name.clone(), text);
+        e.end = true;
+        self.tx
+            .publish(self.subject.to_string(), e.encode(self.format)?)
+            .await?;
+        record(transcript, e).await;
+        Ok(())
+    }
+}
+
+/// Waits until the audio of the reply has finished playing;
+/// returns false if the audio player has gone away meanwhile.
+async fn played(audio_done: &mut watch::Receiver<bool>) -> bool {
+    loop {
+        if audio_done.changed().await.is_err() {
+            return false;
+        }
+        if *audio_done.borrow() {
+            return true;
+        }
+    }
+}
+
+/// Sends the turn to the transcript recorder; once the recorder is gone,
+/// e.g. it has failed, the turns are no longer recorded.
+async fn record(transcript: &Option<Sender<Envelope>>, e: Envelope) {
+    if let Some(transcript) = transcript {
+        let _ = transcript.send(e).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bot, llm, output, signal, stop, supervisor, tts, turn};
+    use bytes::Bytes;
+
+    #[test]
+    fn matches_subjects() {
+        for (pattern, subject, matches) in [
+            ("go", "go", true),
+            ("go", "rust", false),
+            ("go", "go.on", false),
+            ("banter.*.rustbot", "banter.roundtable.rustbot", true),
+            ("banter.*.rustbot", "banter.roundtable.gobot", false),
+            ("banter.*", "banter.roundtable.rustbot", false),
+            ("banter.>", "banter.roundtable.rustbot", true),
+            ("banter.>", "banter", false),
+            (">", "go", true),
+            ("*", "go.on", false),
+        ] {
+            assert_eq!(
+                subject_matches(pattern, subject),
+                matches,
+                "{} {}",
+                pattern,
+                subject
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn buffers_messages_until_subscribed() {
+        let local = Local::default();
+        local
+            .publish("go".into(), Bytes::from("first").into())
+            .await
+            .unwrap();
+        local
+            .publish("rust".into(), Bytes::from("other").into())
+            .await
+            .unwrap();
+
+        let mut go = local.subscribe("go".into()).await.unwrap();
+        local
+            .publish("go".into(), Bytes::from("second").into())
+            .await
+            .unwrap();
+        assert_eq!(go.next().await.unwrap().unwrap().payload, "first");
+        assert_eq!(go.next().await.unwrap().unwrap().payload, "second");
+
+        // NOTE: the messages delivered to a subscriber are not buffered anymore.
+        let mut all = local.subscribe(">".into()).await.unwrap();
+        local
+            .publish("go".into(), Bytes::from("third").into())
+            .await
+            .unwrap();
+        assert_eq!(all.next().await.unwrap().unwrap().payload, "other");
+        assert_eq!(all.next().await.unwrap().unwrap().payload, "third");
+        assert_eq!(go.next().await.unwrap().unwrap().payload, "third");
+    }
+
+    fn config(name: &str, pub_subject: &str, sub_subject: &str) -> bot::Config {
+        bot::Config {
+            jet: Config {
+                durable_name: name.to_string(),
+                pub_subject: pub_subject.to_string(),
+                sub_subject: sub_subject.to_string(),
+                conversation_id: Some("test".to_string()),
+                ..Default::default()
+            },
+            llm: llm::Config {
+                backend: llm::Backend::Scripted,
+                ..Default::default()
+            },
+            tts: tts::Config::default(),
+            audio: output::Config::default(),
+            text_only: true,
+            barge_in: false,
+            timing: turn::Timing::default(),
+            stop: stop::Config {
+                max_turns: Some(5),
+                ..Default::default()
+            },
+            supervisor: supervisor::Config::default(),
+            transcript: None,
+            recording: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bots_take_turns_over_local_transport() {
+        let local = Arc::new(Local::default());
+        let mut spy = local.subscribe(">".into()).await.unwrap();
+        let rustbot = bot::Bot::new(config("rustbot", "go", "rust"), local.clone())
+            .await
+            .unwrap();
+        let gobot = bot::Bot::new(config("gobot", "rust", "go"), local.clone())
+            .await
+            .unwrap();
+        local
+            .publish("go".into(), Bytes::from("Hi gobot!").into())
+            .await
+            .unwrap();
+
+        let (_shutdown, running) = watch::channel(signal::Shutdown::default());
+        let bots =
+            futures::future::try_join(rustbot.run(None, running.clone()), gobot.run(None, running));
+        time::timeout(Duration::from_secs(10), bots)
+            .await
+            .expect("the conversation has not ended")
+            .unwrap();
+
+        let mut turns = Vec::new();
+        loop {
+            let next = time::timeout(Duration::from_secs(1), spy.next()).await;
+            let message = next.expect("the end of the conversation has not been announced");
+            let e = Envelope::decode(&message.unwrap().unwrap()).unwrap();
+            if e.end {
+                break;
+            }
+            turns.push((e.turn, e.sender));
+        }
+        let expected = [
+            (0, ""),
+            (2, "gobot"),
+            (3, "rustbot"),
+            (4, "gobot"),
+            (5, "rustbot"),
+        ];
+        assert_eq!(turns, expected.map(|(n, s)| (n, s.to_string())));
+    }
+
+    /// Fails the first subscription, then subscribes through the local transport.
+    struct Flaky {
+        local: Local,
+        subscriptions: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl Transport for Flaky {
+        async fn publish(&self, subject: String, message: Message) -> Result<()> {
+            self.local.publish(subject, message).await
+        }
+
+        async fn subscribe(&self, subject: String) -> Result<Messages> {
+            *self.subscriptions.lock().unwrap() += 1;
+            if *self.subscriptions.lock().unwrap() == 1 {
+                return Ok(Box::pin(tokio_stream::iter(vec![Err(
+                    "consumer deleted".into()
+                )])));
+            }
+            self.local.subscribe(subject).await
+        }
+    }
+
+    #[tokio::test]
+    async fn reader_subscribes_again_once_restarted() {
+        let flaky = Arc::new(Flaky {
+            local: Local::default(),
+            subscriptions: Mutex::new(0),
+        });
+        let c = config("rustbot", "go", "rust").jet;
+        let coord =
+            turn::Coordinator::new("rustbot".into(), None, turn::Timing::default(), false, 0);
+        let budget = stop::Budget::new(stop::Config::default(), "rustbot".into());
+        let mut stream = Stream::new(flaky.clone(), c, coord, budget).await.unwrap();
+
+        let (prompts_tx, mut prompts_rx) = mpsc::channel(8);
+        let interrupt = watch::Sender::new(Interrupt::None);
+        let (done_tx, done_rx) = watch::channel(false);
+        let res = stream
+            .reader
+            .read(&prompts_tx, &None, &interrupt, done_rx.clone())
+            .await;
+        assert!(res.is_err());
+
+        flaky
+            .publish("rust".into(), Bytes::from("Hi rustbot!").into())
+            .await
+            .unwrap();
+        let read = stream.reader.read(&prompts_tx, &None, &interrupt, done_rx);
+        let received = async {
+            let prompt = prompts_rx.recv().await.unwrap();
+            done_tx.send_replace(true);
+            prompt
+        };
+        let (res, prompt) = tokio::join!(read, received);
+        res.unwrap();
+        assert_eq!(prompt.text, "Hi rustbot!");
+        assert_eq!(*flaky.subscriptions.lock().unwrap(), 2);
+    }
 }
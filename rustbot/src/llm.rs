@@ -1,65 +1,583 @@
-use crate::{history, prelude::*};
+use crate::{
+    bot::Interrupt,
+    envelope::Envelope,
+    history::{self, Role},
+    prelude::*,
+    turn,
+};
+use async_trait::async_trait;
 use bytes::Bytes;
-use ollama_rs::{generation::completion::request::GenerationRequest, Ollama};
+use clap::ValueEnum;
+use ollama_rs::{
+    generation::chat::{request::ChatMessageRequest, ChatMessage, MessageRole},
+    generation::parameters::KeepAlive,
+    headers::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    models::ModelOptions,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use tokio::{
     self,
     sync::mpsc::{Receiver, Sender},
     sync::watch,
-    task::JoinHandle,
+    time::{self, Duration},
 };
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
+
+/// A stream of generated text chunks.
+pub type Chunks = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+/// Chunk is a piece of the reply streamed to jet::Writer and tts::TTS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Chunk {
+    Text(Bytes),
+    /// Marks the end of the reply.
+    End,
+    /// Marks the end of the reply which has been cut short.
+    Interrupted,
+    /// Marks the end of the reply which has failed; it's abandoned.
+    Failed,
+    /// Names the participant who speaks after the reply at the round table.
+    Next(String),
+}
+
+/// Generator generates a streamed reply to the given chat history.
+#[async_trait]
+pub trait Generator: Send + Sync {
+    async fn generate(&self, messages: Vec<history::Message>) -> Result<Chunks>;
+
+    /// Prepares the generator before the conversation starts.
+    async fn prepare(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Generate replies using a local Ollama server.
+    Ollama,
+    /// Replay canned replies; useful for running the bot offline.
+    Scripted,
+}
+
+/// Options tune how the model generates the replies; the model defaults are used when not set.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub min_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    /// Size of the model context in tokens.
+    pub num_ctx: Option<u64>,
+    /// Max number of tokens in the reply; the reply is cut off once it's reached.
+    pub num_predict: Option<i32>,
+    /// Sequences which end the reply once the model generates them.
+    pub stop: Option<Vec<String>>,
+    /// Seed of the random number generator; makes the replies reproducible.
+    pub seed: Option<i32>,
+    /// How long the model stays loaded after the request, e.g. 30s, 5m or 1h; -1 keeps it loaded.
+    pub keep_alive: Option<String>,
+}
+
+impl Options {
+    /// Returns the options, falling back to the other options where they're not set.
+    pub fn or(self, other: Options) -> Options {
+        Options {
+            temperature: self.temperature.or(other.temperature),
+            top_p: self.top_p.or(other.top_p),
+            top_k: self.top_k.or(other.top_k),
+            min_p: self.min_p.or(other.min_p),
+            repeat_penalty: self.repeat_penalty.or(other.repeat_penalty),
+            num_ctx: self.num_ctx.or(other.num_ctx),
+            num_predict: self.num_predict.or(other.num_predict),
+            stop: self.stop.or(other.stop),
+            seed: self.seed.or(other.seed),
+            keep_alive: self.keep_alive.or(other.keep_alive),
+        }
+    }
+
+    fn model_options(&self) -> ModelOptions {
+        let mut o = ModelOptions::default();
+        if let Some(v) = self.temperature {
+            o = o.temperature(v);
+        }
+        if let Some(v) = self.top_p {
+            o = o.top_p(v);
+        }
+        if let Some(v) = self.top_k {
+            o = o.top_k(v);
+        }
+        if let Some(v) = self.min_p {
+            o = o.min_p(v);
+        }
+        if let Some(v) = self.repeat_penalty {
+            o = o.repeat_penalty(v);
+        }
+        if let Some(v) = self.num_ctx {
+            o = o.num_ctx(v);
+        }
+        if let Some(v) = self.num_predict {
+            o = o.num_predict(v);
+        }
+        if let Some(v) = &self.stop {
+            o = o.stop(v.clone());
+        }
+        if let Some(v) = self.seed {
+            o = o.seed(v);
+        }
+        o
+    }
+}
+
+/// Parses how long the model stays loaded, e.g. 30s, 5m or 1h; 0 unloads it and -1 keeps it loaded.
+pub fn parse_keep_alive(s: &str) -> std::result::Result<KeepAlive, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string()))
+        .map_err(|e| format!("invalid keep alive {:?}: {}", s, e))
+}
+
+/// Endpoint is where the Ollama server is found and how the bot authenticates with it.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    pub url: String,
+    /// Bearer token sent along with every request.
+    pub token: Option<String>,
+    /// Headers sent along with every request, e.g. to get through a proxy.
+    pub headers: Vec<(String, String)>,
+    /// Pull the model on startup if the server doesn't have it.
+    pub pull: bool,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint {
+            url: OLLAMA_DEFAULT_URL.to_string(),
+            token: None,
+            headers: Vec::new(),
+            pull: false,
+        }
+    }
+}
+
+impl Endpoint {
+    fn client(&self) -> Result<ollama_rs::Ollama> {
+        let url = parse_ollama_url(&self.url)?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(HeaderName::try_from(name)?, HeaderValue::try_from(value)?);
+        }
+        if let Some(token) = &self.token {
+            let mut value = HeaderValue::try_from(format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        Ok(ollama_rs::Ollama::builder()
+            .url(url.as_str())
+            .request_headers(headers)
+            .build())
+    }
+}
+
+/// Parses the Ollama server URL; the scheme and the port may be left out as in OLLAMA_HOST.
+pub fn parse_ollama_url(s: &str) -> std::result::Result<String, String> {
+    let (scheme, rest) = s.split_once("://").unwrap_or(("http", s));
+    let authority = rest.split('/').next().unwrap_or_default();
+    // NOTE: the IPv6 addresses are bracketed as they're full of colons.
+    let has_port = match authority.rfind(']') {
+        Some(i) => authority[i..].contains(':'),
+        None => authority.contains(':'),
+    };
+    let mut url = url::Url::parse(&format!("{}://{}", scheme, rest))
+        .map_err(|e| format!("invalid Ollama URL {:?}: {}", s, e))?;
+    if !matches!(scheme, "http" | "https") {
+        return Err(format!(
+            "invalid Ollama URL {:?}: expected http or https",
+            s
+        ));
+    }
+    // NOTE: plain HTTP defaults to the Ollama port, HTTPS to its own one as it's usually proxied.
+    if scheme == "http" && !has_port {
+        url.set_port(Some(OLLAMA_DEFAULT_PORT))
+            .map_err(|_| format!("invalid Ollama URL {:?}", s))?;
+    }
+    // NOTE: the API paths are appended to the URL as they are, e.g. behind a proxy at /ollama.
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url.to_string())
+}
+
+/// Parses the header given as NAME:VALUE.
+pub fn parse_header(s: &str) -> std::result::Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME:VALUE, got {:?}", s))?;
+    let (name, value) = (name.trim(), value.trim());
+    HeaderName::try_from(name).map_err(|e| format!("invalid header name {:?}: {}", name, e))?;
+    HeaderValue::try_from(value).map_err(|e| format!("invalid header value of {}: {}", name, e))?;
+    Ok((name.to_string(), value.to_string()))
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
-    pub hist_size: usize,
+    /// History token budget; derived from the model context size when not set.
+    pub hist_tokens: Option<usize>,
+    /// Summarize the messages evicted from history.
+    pub summarize: bool,
     pub model_name: String,
     pub seed_prompt: Option<String>,
+    /// Example exchanges pinned to history after the seed prompt.
+    pub examples: Vec<history::Message>,
+    pub options: Options,
+    pub endpoint: Endpoint,
+    /// Chat history to continue the conversation from.
+    pub history: Vec<history::Message>,
+    pub backend: Backend,
+    pub script: Option<PathBuf>,
+    /// Take part in a round table conversation.
+    pub table: Option<turn::Config>,
+    /// Ask the LLM to say goodbye once the conversation has run its course.
+    pub goodbye: bool,
+    /// Number of times the failed request is retried.
+    pub retries: u32,
+    /// Backoff before the first retry; it doubles with every retry.
+    pub retry_backoff: Duration,
+    /// Max time the request may take.
+    pub timeout: Option<Duration>,
+    /// Models tried in order once the model keeps failing.
+    pub fallback_models: Vec<String>,
+    /// Reply given once all the models have failed.
+    pub canned_reply: Option<String>,
+}
+
+impl Config {
+    /// Returns the longest it may take to give the reply when all the requests fail
+    /// and time out, i.e. until the canned reply is given; it's unbounded without the timeout.
+    pub fn max_reply_time(&self) -> Option<Duration> {
+        let timeout = self.timeout?;
+        let backoff: Duration = (1..=self.retries)
+            .map(|a| self.retry_backoff.saturating_mul(1 << (a - 1).min(16)))
+            .sum();
+        let model = timeout.saturating_mul(self.retries + 1) + backoff;
+        Some(model.saturating_mul(1 + self.fallback_models.len() as u32))
+    }
+
+    /// Returns the history token budget; the history is shared by the model
+    /// and its fallback models, so it must fit the smallest of their contexts.
+    pub fn history_budget(&self) -> usize {
+        self.fallback_models
+            .iter()
+            .map(|m| history_budget(m, &self.options))
+            .fold(history_budget(&self.model_name, &self.options), usize::min)
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            hist_size: HISTORY_SIZE,
+            hist_tokens: None,
+            summarize: false,
             model_name: DEFAULT_MODEL_NAME.to_string(),
             seed_prompt: None,
+            examples: Vec::new(),
+            options: Options::default(),
+            endpoint: Endpoint::default(),
+            history: Vec::new(),
+            backend: Backend::Ollama,
+            script: None,
+            table: None,
+            goodbye: false,
+            retries: LLM_RETRIES,
+            retry_backoff: Duration::from_millis(LLM_RETRY_BACKOFF),
+            timeout: Some(Duration::from_secs(LLM_TIMEOUT)),
+            fallback_models: Vec::new(),
+            canned_reply: Some(CANNED_REPLY.to_string()),
+        }
+    }
+}
+
+pub struct Ollama {
+    client: ollama_rs::Ollama,
+    model_name: String,
+    options: ModelOptions,
+    keep_alive: Option<KeepAlive>,
+    pull: bool,
+}
+
+impl Ollama {
+    pub fn new(model_name: String, c: &Config) -> Result<Self> {
+        let options = &c.options;
+        // NOTE: Ollama serves the models with its own default context size unless told otherwise,
+        // so every model is sent its own; the history budget fits the smallest of them.
+        let num_ctx = context_size(&model_name, options) as u64;
+        Ok(Ollama {
+            client: c.endpoint.client()?,
+            model_name,
+            options: options.model_options().num_ctx(num_ctx),
+            pull: c.endpoint.pull,
+            keep_alive: options
+                .keep_alive
+                .as_deref()
+                .map(parse_keep_alive)
+                .transpose()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Generator for Ollama {
+    async fn generate(&self, messages: Vec<history::Message>) -> Result<Chunks> {
+        let messages = messages
+            .into_iter()
+            .map(|m| {
+                let role = match m.role {
+                    Role::System => MessageRole::System,
+                    Role::User => MessageRole::User,
+                    Role::Assistant => MessageRole::Assistant,
+                };
+                ChatMessage::new(role, m.content)
+            })
+            .collect();
+        let mut req = ChatMessageRequest::new(self.model_name.clone(), messages)
+            .options(self.options.clone());
+        if let Some(keep_alive) = &self.keep_alive {
+            req = req.keep_alive(keep_alive.clone());
+        }
+        let stream = self.client.send_chat_messages_stream(req).await?;
+
+        let model_name = self.model_name.clone();
+        // NOTE: ollama-rs prints the cause of a broken stream to stderr itself
+        // and yields no error value; matching on () makes the build fail once
+        // it does, so that the source can be wrapped here.
+        Ok(Box::pin(stream.map(move |res| match res {
+            Ok(resp) => Ok(resp.message.content),
+            Err(()) => Err(format!("failed reading chat response from {}", model_name).into()),
+        })))
+    }
+    /// Checks the server has the model, pulling it if asked to.
+    async fn prepare(&self) -> Result<()> {
+        let url = self.client.url_str();
+        // NOTE: the server may well come up later, the failed requests are retried.
+        let models = match self.client.list_local_models().await {
+            Ok(models) => models,
+            Err(e) => {
+                eprintln!(
+                    "Failed to check the model {} at {}: {}",
+                    self.model_name, url, e
+                );
+                return Ok(());
+            }
+        };
+        if models.iter().any(|m| same_model(&m.name, &self.model_name)) {
+            return Ok(());
+        }
+        if !self.pull {
+            return Err(format!(
+                "model {} not found at {}; pull it or run with --pull-model",
+                self.model_name, url
+            )
+            .into());
+        }
+        println!("pulling model {} at {}", self.model_name, url);
+        let mut statuses = self
+            .client
+            .pull_model_stream(self.model_name.clone(), false)
+            .await?;
+        let mut last = String::new();
+        while let Some(status) = statuses.next().await {
+            let status = status?;
+            // NOTE: the download progress is reported many times a second.
+            if status.message != last {
+                println!("pulling model {}: {}", self.model_name, status.message);
+                last = status.message;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the model names refer to the same model; the tag defaults to latest.
+fn same_model(a: &str, b: &str) -> bool {
+    let tagged = |m: &str| match m.contains(':') {
+        true => m.to_string(),
+        false => format!("{}:latest", m),
+    };
+    tagged(a) == tagged(b)
+}
+
+/// Scripted replays a fixed list of replies in order, regardless of the prompt.
+pub struct Scripted {
+    replies: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl Scripted {
+    pub fn new(replies: Vec<String>) -> Self {
+        Scripted {
+            replies,
+            next: AtomicUsize::new(0),
         }
     }
+
+    /// Reads the replies from a file which contains one reply per line.
+    pub fn from_file(path: &PathBuf) -> Result<Self> {
+        let replies: Vec<String> = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect();
+        if replies.is_empty() {
+            return Err(format!("no replies found in script {}", path.display()).into());
+        }
+        Ok(Scripted::new(replies))
+    }
+}
+
+impl Default for Scripted {
+    fn default() -> Self {
+        Scripted::new(DEFAULT_SCRIPT.iter().map(|r| r.to_string()).collect())
+    }
+}
+
+#[async_trait]
+impl Generator for Scripted {
+    async fn generate(&self, _messages: Vec<history::Message>) -> Result<Chunks> {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.replies.len();
+        // NOTE: split the reply into words so it's streamed the same way LLMs do it.
+        let words: Vec<Result<String>> = self.replies[idx]
+            .split_inclusive(' ')
+            .map(|w| Ok(w.to_string()))
+            .collect();
+        Ok(Box::pin(tokio_stream::iter(words)))
+    }
+}
+
+/// Returns the size of the model context; unless it's set by the options,
+/// it's the context size the model was trained with.
+pub fn context_size(model_name: &str, options: &Options) -> usize {
+    options.num_ctx.map(|n| n as usize).unwrap_or_else(|| {
+        MODEL_CONTEXT_SIZES
+            .iter()
+            .find(|(prefix, _)| model_name.starts_with(prefix))
+            .map(|(_, size)| *size)
+            .unwrap_or(DEFAULT_CONTEXT_SIZE)
+    })
+}
+
+/// Returns the history token budget for the given model which leaves enough
+/// room in the model context for the reply.
+pub fn history_budget(model_name: &str, options: &Options) -> usize {
+    let ctx_size = context_size(model_name, options);
+    // NOTE: the reply may be longer than the reserve when its length is capped above it.
+    let reserve = match options.num_predict {
+        Some(n) if n > 0 => REPLY_TOKEN_RESERVE.max(n as usize),
+        _ => REPLY_TOKEN_RESERVE,
+    };
+    ctx_size.saturating_sub(reserve)
 }
 
 pub struct LLM {
-    client: Ollama,
     model_name: String,
-    hist_size: usize,
-    seed_prompt: Option<String>,
+    generator: Box<dyn Generator>,
+    /// The fallback models and their generators.
+    fallbacks: Vec<(String, Box<dyn Generator>)>,
+    retries: u32,
+    retry_backoff: Duration,
+    timeout: Option<Duration>,
+    canned_reply: Option<String>,
+    summarize: bool,
+    // NOTE: the history outlives the stream so that it survives its restarts.
+    history: history::History,
+    table: Option<turn::Table>,
 }
 
 impl LLM {
-    pub fn new(c: Config) -> Self {
-        let ollama = Ollama::default();
-        LLM {
-            client: ollama,
+    pub fn new(c: Config) -> Result<Self> {
+        let generator: Box<dyn Generator> = match c.backend {
+            Backend::Ollama => Box::new(Ollama::new(c.model_name.clone(), &c)?),
+            Backend::Scripted => match &c.script {
+                Some(path) => Box::new(Scripted::from_file(path)?),
+                None => Box::<Scripted>::default(),
+            },
+        };
+        // NOTE: the scripted backend has nothing to fall back to.
+        let fallbacks = match c.backend {
+            Backend::Ollama => c
+                .fallback_models
+                .iter()
+                .map(|m| {
+                    let g: Box<dyn Generator> = Box::new(Ollama::new(m.clone(), &c)?);
+                    Ok((m.clone(), g))
+                })
+                .collect::<Result<_>>()?,
+            Backend::Scripted => Vec::new(),
+        };
+        let hist_tokens = c.hist_tokens.unwrap_or_else(|| c.history_budget());
+        let table = c.table.map(turn::Table::new);
+        // NOTE: the bot needs to know who it's talking to at the round table.
+        let mut notes = Vec::new();
+        if let Some(t) = &table {
+            notes.push(t.seed_note());
+        }
+        if c.goodbye {
+            notes.push(GOODBYE_NOTE.to_string());
+        }
+        let seed_prompt = match (c.seed_prompt, notes.is_empty()) {
+            (seed, true) => seed,
+            (Some(seed), false) => Some(format!("{}\n{}", seed, notes.join(" "))),
+            (None, false) => Some(notes.join(" ")),
+        };
+        let mut history = history::History::new(hist_tokens);
+        if let Some(seed_prompt) = seed_prompt {
+            println!("Seed prompt: {}", seed_prompt);
+            history.pin(history::Message::system(seed_prompt));
+        }
+        for m in c.examples {
+            history.pin(m);
+        }
+        for m in c.history {
+            history.add(m);
+        }
+        Ok(LLM {
             model_name: c.model_name,
-            hist_size: c.hist_size,
-            seed_prompt: c.seed_prompt,
+            generator,
+            fallbacks,
+            retries: c.retries,
+            retry_backoff: c.retry_backoff,
+            timeout: c.timeout,
+            canned_reply: c.canned_reply,
+            summarize: c.summarize,
+            history,
+            table,
+        })
+    }
+
+    /// Prepares the generators of the model and the fallback models.
+    pub async fn prepare(&self) -> Result<()> {
+        self.generator.prepare().await?;
+        for (_, g) in &self.fallbacks {
+            g.prepare().await?;
         }
+        Ok(())
     }
 
     pub async fn stream(
-        self,
-        mut prompts: Receiver<String>,
-        jet_chunks: Sender<Bytes>,
-        tts_chunks: Sender<Bytes>,
+        &mut self,
+        prompts: &mut Receiver<Envelope>,
+        jet_chunks: &Sender<Chunk>,
+        tts_chunks: &Option<Sender<Chunk>>,
+        mut interrupt: watch::Receiver<Interrupt>,
         mut done: watch::Receiver<bool>,
     ) -> Result<()> {
         println!("launching LLM stream");
-        use history::History;
-        let mut history = History::new(self.hist_size);
-
-        if let Some(seed_prompt) = self.seed_prompt {
-            println!("Seed prompt: {}", seed_prompt);
-            history.add(seed_prompt.to_string());
-        }
-
         loop {
             tokio::select! {
                 _ = done.changed() => {
@@ -68,34 +586,74 @@ impl LLM {
                     }
                 },
                 Some(prompt) = prompts.recv() => {
-                    history.add(prompt.clone());
-                    let mut stream = self.client
-                        .generate_stream(GenerationRequest::new(
-                            self.model_name.clone(),
-                            history.string(),
-                        ))
-                        .await?;
-
-                    while let Some(res) = stream.next().await {
-                        let responses = res?;
-                        for resp in responses {
-                            let resp_bytes = Bytes::from(resp.response);
-                            let jet_bytes = resp_bytes.clone();
-                            let jet_ch = jet_chunks.clone();
-                            let jet_task: JoinHandle<Result<()>> = tokio::spawn(async move {
-                               jet_ch.send(Bytes::from(jet_bytes)).await?;
-                               Ok(())
-                            });
-                            let tts_bytes = resp_bytes.clone();
-                            let tts_ch = tts_chunks.clone();
-                            let tts_task: JoinHandle<Result<()>> = tokio::spawn(async move {
-                                tts_ch.send(Bytes::from(tts_bytes)).await?;
-                                Ok(())
-                            });
-                            match tokio::try_join!(jet_task, tts_task) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    return Err(Box::new(e));
+                    // NOTE: whatever was interrupted before the prompt arrived is over.
+                    interrupt.borrow_and_update();
+                    let content = match &self.table {
+                        Some(_) => turn::attribute(&prompt),
+                        None => prompt.text.clone(),
+                    };
+                    let mut evicted = self.history.add(history::Message::user(content));
+                    // NOTE: at the round table the bot hears every turn, but only replies when addressed.
+                    let addressed = match self.table.as_mut() {
+                        Some(t) => {
+                            t.heard(&prompt);
+                            t.addressed(&prompt)
+                        }
+                        None => true,
+                    };
+
+                    if addressed {
+                        let mut reply = String::new();
+                        // NOTE: dropping the reply stream cancels the in-flight request.
+                        let interrupted = tokio::select! {
+                            biased;
+                            _ = interrupt.changed() => true,
+                            res = self.reply(self.history.messages(), &mut reply, jet_chunks, tts_chunks) => {
+                                if let Err(e) = res {
+                                    // NOTE: the reply is abandoned so that the next one starts afresh.
+                                    send(Chunk::Failed, jet_chunks, tts_chunks).await?;
+                                    return Err(e);
+                                }
+                                false
+                            }
+                        };
+                        if interrupted {
+                            if let Some(t) = &self.table {
+                                jet_chunks.send(Chunk::Next(t.pick(&reply))).await?;
+                            }
+                            if !reply.is_empty() {
+                                reply.truncate(reply.trim_end().len());
+                                reply.push_str(INTERRUPTED_SUFFIX);
+                                evicted.extend(self.history.add(history::Message::assistant(reply)));
+                            }
+                            send(Chunk::Interrupted, jet_chunks, tts_chunks).await?;
+                        } else {
+                            evicted.extend(self.history.add(history::Message::assistant(reply.clone())));
+                            let mut interrupted = false;
+                            if let Some(t) = &self.table {
+                                let next = tokio::select! {
+                                    biased;
+                                    _ = interrupt.changed() => {
+                                        interrupted = true;
+                                        t.pick(&reply)
+                                    },
+                                    next = self.next_speaker(t, &reply) => next,
+                                };
+                                jet_chunks.send(Chunk::Next(next)).await?;
+                            }
+                            // NOTE: the reply cut short while picking the next speaker is treated as any other.
+                            let end = if interrupted { Chunk::Interrupted } else { Chunk::End };
+                            send(end, jet_chunks, tts_chunks).await?;
+                        }
+                    }
+
+                    if self.summarize && !evicted.is_empty() {
+                        // NOTE: the summary is given up on when shutting down.
+                        tokio::select! {
+                            _ = done.wait_for(|d| *d) => return Ok(()),
+                            res = self.summarize(evicted) => {
+                                if let Err(e) = res {
+                                    eprintln!("Failed to summarize history, the evicted messages are dropped: {}", e);
                                 }
                             }
                         }
@@ -105,3 +663,479 @@ impl LLM {
         }
     }
 }
+
+impl LLM {
+    /// Generates the reply to the chat history and streams it into the chunk channels.
+    /// The failed request is retried with backoff, then the fallback models are tried
+    /// in turn and once they've all failed, the canned reply is given. The reply which
+    /// fails midway is cut short as there's no taking back what's been said.
+    async fn reply(
+        &self,
+        messages: Vec<history::Message>,
+        reply: &mut String,
+        jet_chunks: &Sender<Chunk>,
+        tts_chunks: &Option<Sender<Chunk>>,
+    ) -> Result<()> {
+        let models = std::iter::once((&self.model_name, &self.generator))
+            .chain(self.fallbacks.iter().map(|(m, g)| (m, g)));
+        let mut failure = None;
+        for (model, generator) in models {
+            for attempt in 0..=self.retries {
+                self.backoff(attempt).await;
+                let res = self
+                    .generate(
+                        generator.as_ref(),
+                        messages.clone(),
+                        reply,
+                        jet_chunks,
+                        tts_chunks,
+                    )
+                    .await;
+                let Err(e) = res else {
+                    return Ok(());
+                };
+                if !reply.is_empty() {
+                    eprintln!("Failed to finish the reply of {}: {}", model, e);
+                    return Ok(());
+                }
+                eprintln!(
+                    "Failed to generate the reply of {} (attempt {} of {}): {}",
+                    model,
+                    attempt + 1,
+                    self.retries + 1,
+                    e
+                );
+                failure = Some(e);
+            }
+        }
+        let e = failure.unwrap_or_else(|| "no LLM model to reply with".into());
+        let Some(canned) = &self.canned_reply else {
+            return Err(e);
+        };
+        println!(
+            "giving the canned reply as all the LLM models have failed: {}",
+            e
+        );
+        reply.push_str(canned);
+        send(
+            Chunk::Text(Bytes::from(canned.clone())),
+            jet_chunks,
+            tts_chunks,
+        )
+        .await
+    }
+
+    /// Makes a single request for the reply, which times out unless it's done in time.
+    async fn generate(
+        &self,
+        generator: &dyn Generator,
+        messages: Vec<history::Message>,
+        reply: &mut String,
+        jet_chunks: &Sender<Chunk>,
+        tts_chunks: &Option<Sender<Chunk>>,
+    ) -> Result<()> {
+        self.timed(async {
+            let mut chunks = generator.generate(messages).await?;
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                if chunk.is_empty() {
+                    continue;
+                }
+                reply.push_str(&chunk);
+                send(Chunk::Text(Bytes::from(chunk)), jet_chunks, tts_chunks).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Asks the LLM who speaks after the reply at the round table. The failed request
+    /// is retried with backoff; once it keeps failing the next speaker is picked without the LLM.
+    async fn next_speaker(&self, t: &turn::Table, reply: &str) -> String {
+        let Some(messages) = t.ask(self.history.messages()) else {
+            return t.pick(reply);
+        };
+        let answer = self
+            .complete(messages, "pick the next speaker")
+            .await
+            .unwrap_or_default();
+        t.answered(&answer, reply)
+    }
+
+    /// Generates the text which isn't streamed to anybody, e.g. the history summary.
+    /// The failed request is retried with backoff.
+    async fn complete(&self, messages: Vec<history::Message>, what: &str) -> Result<String> {
+        let mut failure = None;
+        for attempt in 0..=self.retries {
+            self.backoff(attempt).await;
+            let mut text = String::new();
+            let res = self
+                .timed(async {
+                    let mut chunks = self.generator.generate(messages.clone()).await?;
+                    while let Some(chunk) = chunks.next().await {
+                        text.push_str(&chunk?);
+                    }
+                    Ok(())
+                })
+                .await;
+            match res {
+                Ok(()) => return Ok(text),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to {} (attempt {} of {}): {}",
+                        what,
+                        attempt + 1,
+                        self.retries + 1,
+                        e
+                    );
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| format!("failed to {}", what).into()))
+    }
+
+    /// Waits before retrying the failed request; the backoff doubles with every retry.
+    async fn backoff(&self, attempt: u32) {
+        if attempt > 0 {
+            time::sleep(
+                self.retry_backoff
+                    .saturating_mul(1 << (attempt - 1).min(16)),
+            )
+            .await;
+        }
+    }
+
+    /// Fails the request unless it's done in time.
+    async fn timed(&self, request: impl Future<Output = Result<()>>) -> Result<()> {
+        match self.timeout {
+            Some(t) => time::timeout(t, request)
+                .await
+                .unwrap_or_else(|_| Err(format!("timed out after {}s", t.as_secs()).into())),
+            None => request.await,
+        }
+    }
+
+    /// Folds the evicted messages into the rolling history summary.
+    async fn summarize(&mut self, evicted: Vec<history::Message>) -> Result<()> {
+        let mut text = String::new();
+        if let Some(summary) = self.history.summary() {
+            text.push_str(&summary.content);
+            text.push_str("\n\n");
+        }
+        for m in evicted {
+            text.push_str(&format!("{}: {}\n", m.role, m.content));
+        }
+        let messages = vec![
+            history::Message::system(SUMMARY_PROMPT.to_string()),
+            history::Message::user(text),
+        ];
+        let summary = self.complete(messages, "summarize history").await?;
+        self.history.set_summary(history::Message::system(format!(
+            "{}{}",
+            SUMMARY_PREFIX, summary
+        )));
+        Ok(())
+    }
+}
+
+async fn send(
+    chunk: Chunk,
+    jet_chunks: &Sender<Chunk>,
+    tts_chunks: &Option<Sender<Chunk>>,
+) -> Result<()> {
+    let Some(tts_ch) = tts_chunks else {
+        // NOTE: text-only mode: there is nobody to speak the reply.
+        jet_chunks.send(chunk).await?;
+        return Ok(());
+    };
+    // NOTE: we don't spawn the sends so that when the reply is interrupted
+    // no chunk can arrive after the interruption marker. Once the TTS is gone,
+    // e.g. it has failed for good, the bot carries on text-only.
+    let (res, _) = tokio::join!(jet_chunks.send(chunk.clone()), tts_ch.send(chunk));
+    res?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    /// Fails every request.
+    struct Failing(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Generator for Failing {
+        async fn generate(&self, _messages: Vec<history::Message>) -> Result<Chunks> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err("unavailable".into())
+        }
+    }
+
+    /// Starts the reply, but never finishes it.
+    struct Hanging;
+
+    #[async_trait]
+    impl Generator for Hanging {
+        async fn generate(&self, _messages: Vec<history::Message>) -> Result<Chunks> {
+            let start = tokio_stream::iter(vec![Ok("Hello ".to_string())]);
+            Ok(Box::pin(start.chain(tokio_stream::pending())))
+        }
+    }
+
+    /// Replies to the last message and summarizes when asked to; it notes down the requests.
+    struct Echo(Arc<Mutex<Vec<Vec<history::Message>>>>);
+
+    #[async_trait]
+    impl Generator for Echo {
+        async fn generate(&self, messages: Vec<history::Message>) -> Result<Chunks> {
+            let reply = match messages.first() {
+                Some(m) if m.content == SUMMARY_PROMPT => "short summary".to_string(),
+                _ => format!("reply to {}", messages.last().unwrap().content),
+            };
+            self.0.lock().unwrap().push(messages);
+            Ok(Box::pin(tokio_stream::iter(vec![Ok(reply)])))
+        }
+    }
+
+    /// Replies right away, but never finishes the summary.
+    struct Forgetful;
+
+    #[async_trait]
+    impl Generator for Forgetful {
+        async fn generate(&self, messages: Vec<history::Message>) -> Result<Chunks> {
+            if messages[0].content == SUMMARY_PROMPT {
+                return Ok(Box::pin(tokio_stream::pending()));
+            }
+            Ok(Box::pin(tokio_stream::iter(vec![Ok("ok".to_string())])))
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            backend: Backend::Scripted,
+            retries: 1,
+            retry_backoff: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    fn llm(c: Config, generator: Box<dyn Generator>, fallbacks: Vec<Box<dyn Generator>>) -> LLM {
+        let mut llm = LLM::new(c).unwrap();
+        llm.generator = generator;
+        llm.fallbacks = fallbacks
+            .into_iter()
+            .enumerate()
+            .map(|(i, g)| (format!("fallback{}", i), g))
+            .collect();
+        llm
+    }
+
+    /// Streams the replies to the prompts and returns the chunks; the reply
+    /// is interrupted by the operator as soon as its first chunk arrives.
+    async fn stream(llm: &mut LLM, prompts: &[&str], interrupt: bool) -> (Result<()>, Vec<Chunk>) {
+        let (prompts_tx, mut prompts_rx) = mpsc::channel(8);
+        let (chunks_tx, mut chunks_rx) = mpsc::channel(64);
+        let interrupts = watch::Sender::new(Interrupt::None);
+        let (done_tx, done_rx) = watch::channel(false);
+        for p in prompts {
+            prompts_tx.send(Envelope::new(p.to_string())).await.unwrap();
+        }
+        let collect = async {
+            let mut chunks = Vec::new();
+            let mut replies = 0;
+            while replies < prompts.len() {
+                let chunk = chunks_rx.recv().await.unwrap();
+                match chunk {
+                    Chunk::Text(_) if interrupt => {
+                        interrupts.send_replace(Interrupt::Operator);
+                    }
+                    Chunk::End | Chunk::Interrupted | Chunk::Failed => replies += 1,
+                    _ => {}
+                }
+                chunks.push(chunk);
+            }
+            done_tx.send_replace(true);
+            chunks
+        };
+        let receiver = interrupts.subscribe();
+        tokio::join!(
+            llm.stream(&mut prompts_rx, &chunks_tx, &None, receiver, done_rx),
+            collect
+        )
+    }
+
+    fn text(s: &str) -> Chunk {
+        Chunk::Text(Bytes::from(s.to_string()))
+    }
+
+    #[tokio::test]
+    async fn streams_the_scripted_replies() {
+        let script = vec!["Hello there.".to_string(), "Bye now.".to_string()];
+        let mut llm = llm(config(), Box::new(Scripted::new(script)), Vec::new());
+        let (res, chunks) = stream(&mut llm, &["hi", "bye"], false).await;
+        res.unwrap();
+        assert_eq!(
+            chunks,
+            [
+                text("Hello "),
+                text("there."),
+                Chunk::End,
+                text("Bye "),
+                text("now."),
+                Chunk::End
+            ]
+        );
+        assert_eq!(
+            llm.history.messages(),
+            [
+                history::Message::user("hi".to_string()),
+                history::Message::assistant("Hello there.".to_string()),
+                history::Message::user("bye".to_string()),
+                history::Message::assistant("Bye now.".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_once_the_retries_are_exhausted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fallback = Scripted::new(vec!["Fallback.".to_string()]);
+        let mut llm = llm(
+            config(),
+            Box::new(Failing(calls.clone())),
+            vec![Box::new(fallback)],
+        );
+        let (res, chunks) = stream(&mut llm, &["hi"], false).await;
+        res.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(chunks, [text("Fallback."), Chunk::End]);
+    }
+
+    #[tokio::test]
+    async fn gives_the_canned_reply_once_all_the_models_fail() {
+        let (calls, fallback_calls) =
+            (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut llm = llm(
+            config(),
+            Box::new(Failing(calls.clone())),
+            vec![Box::new(Failing(fallback_calls.clone()))],
+        );
+        let (res, chunks) = stream(&mut llm, &["hi"], false).await;
+        res.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(fallback_calls.load(Ordering::Relaxed), 2);
+        assert_eq!(chunks, [text(CANNED_REPLY), Chunk::End]);
+        assert_eq!(
+            llm.history.messages().last(),
+            Some(&history::Message::assistant(CANNED_REPLY.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_the_reply_without_the_canned_reply() {
+        let c = Config {
+            canned_reply: None,
+            ..config()
+        };
+        let mut llm = llm(c, Box::new(Failing(Arc::default())), Vec::new());
+        let (res, chunks) = stream(&mut llm, &["hi"], false).await;
+        assert!(res.is_err());
+        assert_eq!(chunks, [Chunk::Failed]);
+    }
+
+    #[tokio::test]
+    async fn marks_the_interrupted_reply() {
+        let mut llm = llm(config(), Box::new(Hanging), Vec::new());
+        let (res, chunks) = stream(&mut llm, &["hi"], true).await;
+        res.unwrap();
+        assert_eq!(chunks, [text("Hello "), Chunk::Interrupted]);
+        assert_eq!(
+            llm.history.messages().last(),
+            Some(&history::Message::assistant(format!(
+                "Hello{}",
+                INTERRUPTED_SUFFIX
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn summarizes_the_evicted_history() {
+        let c = Config {
+            hist_tokens: Some(20),
+            summarize: true,
+            ..config()
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut llm = llm(c, Box::new(Echo(requests.clone())), Vec::new());
+        let (res, _) = stream(&mut llm, &["first prompt", "second prompt"], false).await;
+        res.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let evicted = &requests[2][1].content;
+        assert!(evicted.contains("user: first prompt"), "{}", evicted);
+        assert!(
+            evicted.contains("assistant: reply to first prompt"),
+            "{}",
+            evicted
+        );
+        assert_eq!(
+            llm.history.messages(),
+            [
+                history::Message::system(format!("{}short summary", SUMMARY_PREFIX)),
+                history::Message::user("second prompt".to_string()),
+                history::Message::assistant("reply to second prompt".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_ollama_urls() {
+        for (s, url) in [
+            ("localhost", "http://localhost:11434/"),
+            ("0.0.0.0:8080", "http://0.0.0.0:8080/"),
+            ("[::1]", "http://[::1]:11434/"),
+            ("http://ollama", "http://ollama:11434/"),
+            ("https://ollama.example", "https://ollama.example/"),
+            (
+                "https://proxy.example/ollama",
+                "https://proxy.example/ollama/",
+            ),
+            ("http://proxy:8080/ollama/", "http://proxy:8080/ollama/"),
+        ] {
+            assert_eq!(parse_ollama_url(s).unwrap(), url, "{}", s);
+        }
+        assert!(parse_ollama_url("ftp://ollama").is_err());
+    }
+
+    #[test]
+    fn fits_the_history_into_the_smallest_model_context() {
+        let mut c = Config {
+            model_name: "llama3:latest".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(c.history_budget(), 8192 - REPLY_TOKEN_RESERVE);
+        c.fallback_models = vec!["phi3:latest".to_string(), "mistral:latest".to_string()];
+        assert_eq!(c.history_budget(), 4096 - REPLY_TOKEN_RESERVE);
+        c.options.num_ctx = Some(16384);
+        assert_eq!(c.history_budget(), 16384 - REPLY_TOKEN_RESERVE);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_the_hung_summary() {
+        let c = Config {
+            hist_tokens: Some(10),
+            summarize: true,
+            timeout: Some(Duration::from_millis(20)),
+            ..config()
+        };
+        let mut llm = llm(c, Box::new(Forgetful), Vec::new());
+        let (res, chunks) = stream(&mut llm, &["first prompt", "second prompt"], false).await;
+        res.unwrap();
+        assert_eq!(chunks, [text("ok"), Chunk::End, text("ok"), Chunk::End]);
+        assert!(llm.history.summary().is_none());
+    }
+}
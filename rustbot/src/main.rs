@@ -1,90 +1,414 @@
+#![allow(clippy::upper_case_acronyms)]
+
 use bytes::Bytes;
-use clap::Parser;
+use futures::future::try_join_all;
+use jet::Transport;
 use prelude::*;
-use rodio::{OutputStream, Sink};
-use tokio::{
-    self, io,
-    sync::{mpsc, watch},
-};
+use rodio::OutputStream;
+use std::{sync::Arc, time::Duration};
+use tokio::{self, sync::watch};
 
 mod audio;
-mod buffer;
+mod bot;
 mod cli;
+mod config;
+mod envelope;
 mod history;
 mod jet;
 mod llm;
+mod output;
+mod persona;
 mod prelude;
+mod record;
+mod segment;
 mod signal;
+mod stop;
+mod supervisor;
+mod transcript;
 mod tts;
+mod turn;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = cli::App::parse();
-
-    let seed_prompt = args.prompt.seed.unwrap();
-
-    // NOTE: we could also add Stream::builder to the jet module
-    // and instead of passing config we could build it by chaining methods.
-    let c = jet::Config {
-        durable_name: args.bot.name,
-        stream_name: args.bot.stream_name,
-        pub_subject: args.bot.pub_subject,
-        sub_subject: args.bot.sub_subject,
-        ..jet::Config::default()
+    let (args, matches, profile) = config::parse();
+    if let Some(cli::Command::Config(cli::Config::Dump)) = args.command {
+        print!("{}", config::dump(&matches));
+        return Ok(());
+    }
+
+    let resumed = match &args.transcript.resume {
+        Some(path) => transcript::load(path)?,
+        None => Vec::new(),
     };
-    let s = jet::Stream::new(c).await?;
-
-    // NOTE: we could also add LLM::builder to the llm module
-    // and instead of passing config we could build it by chaining methods.
-    let c = llm::Config {
-        hist_size: args.llm.hist_size,
-        model_name: args.llm.model_name,
-        seed_prompt: Some(seed_prompt),
-        ..llm::Config::default()
+    let last = resumed.last();
+
+    let table = match args.table.participants.is_empty() {
+        true => None,
+        false => Some(turn::Config::new(
+            args.persona.name.clone(),
+            args.table.participants,
+            args.table.turn_policy,
+            args.table.moderator,
+        )?),
     };
-    let l = llm::LLM::new(c);
 
-    // NOTE: we could also add TTS::builder to the tts module
-    // and instead of passing config we could build it by chaining methods.
-    let c = tts::Config {
-        voice_id: Some(args.tts.voice_id),
-        ..tts::Config::default()
+    let vars = persona::Vars {
+        name: args.persona.name.clone(),
+        peer_name: args
+            .persona
+            .peer_name
+            .clone()
+            .unwrap_or_else(|| match &table {
+                Some(t) => peers(t, &t.name),
+                None => PEER_BOT_NAME.to_string(),
+            }),
+        topic: args.persona.topic.clone(),
     };
-    let t = tts::TTS::new(c);
+    let seed_prompt = persona::render(args.persona.seed.as_deref().unwrap_or_default(), &vars)
+        .map_err(|e| format!("Failed to render the seed prompt: {}", e))?;
+    let profile = profile.unwrap_or_default();
+    let examples = profile
+        .examples(&vars)
+        .map_err(|e| format!("Failed to render the persona examples: {}", e))?;
+
+    let mut c = bot::Config {
+        // NOTE: we could also add Stream::builder to the jet module
+        // and instead of passing config we could build it by chaining methods.
+        jet: jet::Config {
+            nats_url: args.jet.nats_url,
+            nats: jet::Nats {
+                user: args.jet.nats_user,
+                password: args.jet.nats_password,
+                token: args.jet.nats_token,
+                nkey: args.jet.nats_nkey,
+                creds_file: args.jet.nats_creds,
+                tls_ca: args.jet.nats_tls_ca,
+                tls_cert: args.jet.nats_tls_cert,
+                tls_key: args.jet.nats_tls_key,
+                require_tls: args.jet.nats_require_tls,
+                connect_timeout: Duration::from_secs(args.jet.nats_connect_timeout),
+                max_reconnects: args.jet.nats_max_reconnects,
+                reconnect_backoff: Duration::from_millis(args.jet.nats_reconnect_backoff),
+                max_reconnect_backoff: Duration::from_millis(args.jet.nats_max_reconnect_backoff),
+            },
+            durable_name: args.persona.name.clone(),
+            stream_name: args.jet.stream_name,
+            pub_subject: args.jet.pub_subject,
+            sub_subject: args.jet.sub_subject,
+            conversation_id: args
+                .jet
+                .conversation_id
+                .or_else(|| last.map(|e| e.conversation_id.clone())),
+            turn: last.map(|e| e.turn).unwrap_or_default(),
+            model_name: Some(args.llm.model_name.clone()),
+            // NOTE: the gobot feeds whatever it receives to its LLM, so it only gets the raw text;
+            // the metadata travels in the message headers which it ignores.
+            wire_format: args.jet.wire_format.unwrap_or(
+                match (args.jet.transport, table.is_none()) {
+                    (jet::Backend::JetStream, true) => envelope::WireFormat::Text,
+                    _ => envelope::WireFormat::Json,
+                },
+            ),
+            discard_partial: args.bot.discard_partial,
+        },
+        // NOTE: we could also add LLM::builder to the llm module
+        // and instead of passing config we could build it by chaining methods.
+        llm: llm::Config {
+            hist_tokens: args.llm.hist_tokens,
+            summarize: args.llm.summarize,
+            model_name: args.llm.model_name,
+            seed_prompt: Some(seed_prompt),
+            examples,
+            options: llm::Options {
+                temperature: args.llm.temperature,
+                top_p: args.llm.top_p,
+                top_k: args.llm.top_k,
+                min_p: args.llm.min_p,
+                repeat_penalty: args.llm.repeat_penalty,
+                num_ctx: args.llm.num_ctx,
+                num_predict: args.llm.num_predict,
+                stop: (!args.llm.stop_sequence.is_empty()).then_some(args.llm.stop_sequence),
+                seed: args.llm.llm_seed,
+                keep_alive: args.llm.keep_alive,
+            }
+            .or(profile.options),
+            endpoint: llm::Endpoint {
+                url: args.llm.ollama_url,
+                token: args.llm.ollama_token,
+                headers: args.llm.ollama_header,
+                pull: args.llm.pull_model,
+            },
+            history: transcript::history(&resumed, &args.persona.name, table.is_some()),
+            backend: args.llm.llm_backend,
+            script: args.llm.script,
+            table,
+            goodbye: args.stop.goodbye,
+            retries: args.llm.llm_retries,
+            retry_backoff: Duration::from_millis(args.llm.llm_retry_backoff),
+            timeout: (args.llm.llm_timeout > 0).then(|| Duration::from_secs(args.llm.llm_timeout)),
+            fallback_models: args.llm.fallback_model,
+            canned_reply: (!args.llm.no_canned_reply).then_some(args.llm.canned_reply),
+        },
+        // NOTE: we could also add TTS::builder to the tts module
+        // and instead of passing config we could build it by chaining methods.
+        tts: tts::Config {
+            voice_id: Some(args.persona.voice_id.clone()),
+            quality: Some(args.tts.tts_quality),
+            speed: Some(args.tts.tts_speed),
+            sample_rate: Some(args.tts.tts_sample_rate),
+            buf_size: args.tts.tts_buffer_size,
+            max_latency: Duration::from_millis(args.tts.tts_max_latency),
+            backend: args.tts.tts_backend,
+            retries: args.tts.tts_retries,
+            retry_backoff: Duration::from_millis(args.tts.tts_retry_backoff),
+            timeout: (args.tts.tts_timeout > 0).then(|| Duration::from_secs(args.tts.tts_timeout)),
+        },
+        audio: output::Config {
+            backend: args.audio.audio_output,
+            file: args
+                .audio
+                .audio_file
+                .unwrap_or_else(|| format!("{}.wav", args.persona.name).into()),
+            interval: Duration::from_millis(args.audio.audio_interval),
+        },
+        text_only: args.audio.text_only,
+        barge_in: args.bot.barge_in,
+        timing: turn::Timing {
+            turn_timeout: (args.bot.turn_timeout > 0)
+                .then(|| Duration::from_secs(args.bot.turn_timeout)),
+            kickstart_after: args.bot.kickstart_after.map(Duration::from_secs),
+            start: args.bot.start,
+        },
+        stop: stop::Config {
+            max_turns: args.stop.max_turns,
+            max_duration: args.stop.max_duration.map(Duration::from_secs),
+            max_tokens: args.stop.max_tokens,
+            goodbye: args.stop.goodbye,
+        },
+        supervisor: supervisor::Config {
+            policies: args.supervisor.restart_policy,
+            backoff: Duration::from_millis(args.supervisor.restart_backoff),
+            max_restarts: args.supervisor.max_restarts,
+            ..Default::default()
+        },
+        transcript: args.transcript.transcript.or(args.transcript.resume),
+        recording: args.recording.record_dir.map(|dir| record::Config {
+            dir,
+            stitch: args.recording.record_stitch,
+        }),
+    };
+
+    // NOTE: the turn timeout must give the LLM requests time to fail and the canned reply to be given.
+    if let (Some(turn), Some(reply)) = (c.timing.turn_timeout, c.llm.max_reply_time()) {
+        if turn <= reply {
+            eprintln!(
+                "the turn timeout of {}s may cut the reply short before the failed LLM requests \
+                are given up on after {}s; consider raising --turn-timeout",
+                turn.as_secs(),
+                reply.as_secs()
+            );
+        }
+    }
 
-    let (prompts_tx, prompts_rx) = mpsc::channel::<String>(32);
-    let (jet_chunks_tx, jet_chunks_rx) = mpsc::channel::<Bytes>(32);
-    let (tts_chunks_tx, tts_chunks_rx) = mpsc::channel::<Bytes>(32);
-    let (aud_done_tx, aud_done_rx) = watch::channel(false);
+    if let Some(t) = &c.llm.table {
+        // NOTE: all the participants must talk in the same conversation.
+        let conv = c
+            .jet
+            .conversation_id
+            .get_or_insert_with(|| ROUND_TABLE_CONVERSATION_ID.to_string());
+        c.jet.pub_subject = turn::subject(conv, &t.name)?;
+        c.jet.sub_subject = turn::subject(conv, "*")?;
+    }
+
+    // NOTE: used for the shutdown when SIGINT or SIGTERM is trapped.
+    let (watch_tx, watch_rx) = watch::channel(signal::Shutdown::default());
 
-    // NOTE: used for cancellation when SIGINT is trapped.
-    let (watch_tx, watch_rx) = watch::channel(false);
-    let jet_wr_watch_rx = watch_rx.clone();
-    let jet_rd_watch_rx = watch_rx.clone();
-    let tts_watch_rx = watch_rx.clone();
-    let aud_watch_rx = watch_rx.clone();
+    if c.audio.backend == output::Backend::Stdout && !c.text_only {
+        // NOTE: claim stdout for the audio before we print anything.
+        output::pcm_stdout()?;
+    }
 
     println!("launching workers");
 
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    let (audio_wr, audio_rd) = io::duplex(1024);
+    // NOTE: the output stream must be kept alive for as long as the audio is playing.
+    let mut device = None;
+    if c.audio.backend == output::Backend::Rodio && !c.text_only {
+        // NOTE: containers and CI machines usually don't have any audio device.
+        match OutputStream::try_default() {
+            Ok(stream) => device = Some(stream),
+            Err(e) => {
+                println!(
+                    "no audio device available ({}), falling back to null audio output",
+                    e
+                );
+                c.audio.backend = output::Backend::Null;
+            }
+        }
+    }
+    let audio = device.as_ref().map(|(_, handle)| handle);
+    let sig_handler = tokio::spawn(signal::trap(
+        watch_tx,
+        Duration::from_secs(args.bot.grace_period),
+    ));
 
-    let tts_stream = tokio::spawn(t.stream(audio_wr, tts_chunks_rx, tts_watch_rx));
-    let llm_stream = tokio::spawn(l.stream(prompts_rx, jet_chunks_tx, tts_chunks_tx, watch_rx));
-    let jet_write = tokio::spawn(s.writer.write(jet_chunks_rx, aud_done_rx, jet_wr_watch_rx));
-    let jet_read = tokio::spawn(s.reader.read(prompts_tx, jet_rd_watch_rx));
-    let audio_task = tokio::spawn(audio::play(audio_rd, sink, aud_done_tx, aud_watch_rx));
-    let sig_handler = tokio::spawn(signal::trap(watch_tx));
+    let res = match args.jet.transport {
+        jet::Backend::JetStream => {
+            let js = jet::JetStream::new(&c.jet).await?;
+            let b = bot::Bot::new(c, Arc::new(js)).await?;
+            if args.bot.barge_in {
+                signal::operator(vec![b.interrupter()]);
+            }
+            b.run(audio, watch_rx).await
+        }
+        jet::Backend::Local => {
+            let local = Arc::new(jet::Local::default());
+            let (others, subject, kickoff) = match &c.llm.table {
+                Some(t) => {
+                    let others = t
+                        .participants
+                        .iter()
+                        .filter(|p| **p != t.name)
+                        .enumerate()
+                        .map(|(i, p)| participant(&c, t, p, i, &args.persona))
+                        .collect::<Result<Vec<_>>>()?;
+                    let conv = c.jet.conversation_id.as_deref().unwrap_or_default();
+                    let subject = turn::subject(conv, ROUND_TABLE_OPERATOR)?;
+                    (others, subject, ROUND_TABLE_KICKOFF_PROMPT)
+                }
+                None => {
+                    let peer_c = peer(&c, &args.persona)?;
+                    let subject = peer_c.jet.sub_subject.clone();
+                    (vec![peer_c], subject, DEFAULT_KICKOFF_PROMPT)
+                }
+            };
+            let mut bots = vec![bot::Bot::new(c, local.clone()).await?];
+            for oc in others {
+                bots.push(bot::Bot::new(oc, local.clone()).await?);
+            }
+            // NOTE: kick off the conversation the same way a human would prompt the bots.
+            let kickoff = args.bot.kickoff.unwrap_or_else(|| kickoff.to_string());
+            local.publish(subject, Bytes::from(kickoff).into()).await?;
+            if args.bot.barge_in {
+                signal::operator(bots.iter().map(bot::Bot::interrupter).collect());
+            }
+            try_join_all(bots.into_iter().map(|b| b.run(audio, watch_rx.clone())))
+                .await
+                .map(|_| ())
+        }
+    };
 
     // NOTE: we're not waiting for the signal handler here:
-    // we abort it once any of the spawn worker tasks exits.
-    match tokio::try_join!(tts_stream, llm_stream, jet_write, jet_read, audio_task) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("Error running bot: {}", e);
-        }
+    // we abort it once any of the bot workers exits.
+    if let Err(e) = res {
+        println!("Error running bot: {}", e);
     }
     sig_handler.abort();
     Ok(())
 }
+
+/// Returns the config of the in-process peer bot which plays the role of the gobot.
+fn peer(c: &bot::Config, a: &cli::Persona) -> Result<bot::Config> {
+    let mut peer_c = sibling(
+        c,
+        PEER_BOT_NAME,
+        DEFAULT_PEER_SEED_PROMPT,
+        DEFAULT_PEER_VOICE_ID,
+    );
+    peer_c.jet.pub_subject = c.jet.sub_subject.clone();
+    peer_c.jet.sub_subject = c.jet.pub_subject.clone();
+    personify(peer_c, a, c.jet.durable_name.clone())
+}
+
+/// Returns the config of the in-process bot taking part in the round table.
+fn participant(
+    c: &bot::Config,
+    t: &turn::Config,
+    name: &str,
+    i: usize,
+    a: &cli::Persona,
+) -> Result<bot::Config> {
+    let conv = c.jet.conversation_id.as_deref().unwrap_or_default();
+    let seed_prompt = match name {
+        BOT_NAME => DEFAULT_SEED_PROMPT,
+        PEER_BOT_NAME => DEFAULT_PEER_SEED_PROMPT,
+        _ => ROUND_TABLE_SEED_PROMPT,
+    };
+    // NOTE: the voices alternate so that the neighbours around the table sound different.
+    let voice_id = [DEFAULT_PEER_VOICE_ID, DEFAULT_VOICE_ID][i % 2];
+    let mut participant_c = sibling(c, name, seed_prompt, voice_id);
+    participant_c.jet.pub_subject = turn::subject(conv, name)?;
+    participant_c.llm.table = Some(t.participant(name));
+    personify(participant_c, a, peers(t, name))
+}
+
+/// Returns the config of another in-process bot which shares the settings of the bot,
+/// but speaks with its own name, seed prompt and voice.
+fn sibling(c: &bot::Config, name: &str, seed_prompt: &str, voice_id: &str) -> bot::Config {
+    bot::Config {
+        jet: jet::Config {
+            durable_name: name.to_string(),
+            ..c.jet.clone()
+        },
+        llm: llm::Config {
+            seed_prompt: Some(seed_prompt.to_string()),
+            examples: Vec::new(),
+            history: Vec::new(),
+            ..c.llm.clone()
+        },
+        tts: tts::Config {
+            voice_id: Some(voice_id.to_string()),
+            ..c.tts.clone()
+        },
+        audio: output::Config {
+            file: c.audio.file.with_file_name(format!("{}.wav", name)),
+            ..c.audio.clone()
+        },
+        // NOTE: only the bot itself starts the conversation.
+        timing: turn::Timing {
+            start: false,
+            ..c.timing.clone()
+        },
+        transcript: None,
+        ..c.clone()
+    }
+}
+
+/// Applies the persona file named after the in-process bot, if there's any, and renders its prompts.
+fn personify(mut c: bot::Config, a: &cli::Persona, peer_name: String) -> Result<bot::Config> {
+    let name = c.jet.durable_name.clone();
+    let vars = persona::Vars {
+        name: name.clone(),
+        peer_name,
+        topic: a.topic.clone(),
+    };
+    let render = |e| format!("Failed to render the persona of {}: {}", name, e);
+    if let Some(p) = persona::find(&a.persona_dir, &name)? {
+        c.llm.examples = p.examples(&vars).map_err(render)?;
+        if p.prompt.is_some() {
+            c.llm.seed_prompt = p.prompt;
+        }
+        if let Some(model_name) = p.model_name {
+            c.jet.model_name = Some(model_name.clone());
+            c.llm.model_name = model_name;
+        }
+        if p.voice_id.is_some() {
+            c.tts.voice_id = p.voice_id;
+        }
+        c.llm.options = p.options.or(c.llm.options);
+    }
+    if let Some(seed) = &c.llm.seed_prompt {
+        c.llm.seed_prompt = Some(persona::render(seed, &vars).map_err(render)?);
+    }
+    Ok(c)
+}
+
+/// Returns the names of the round table participants the bot talks to.
+fn peers(t: &turn::Config, name: &str) -> String {
+    let others: Vec<&str> = t
+        .participants
+        .iter()
+        .map(String::as_str)
+        .filter(|p| *p != name)
+        .collect();
+    others.join(", ")
+}
@@ -0,0 +1,264 @@
+use crate::prelude::*;
+use clap::ValueEnum;
+use rodio::{buffer::SamplesBuffer, OutputStreamHandle, Sink};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Output plays the decoded audio.
+pub trait Output: Send + Sync {
+    /// Queues the interleaved samples for playback.
+    fn append(&self, channels: u16, sample_rate: u32, samples: Vec<f32>) -> Result<()>;
+    /// Returns true once all the queued audio has been played.
+    fn empty(&self) -> bool;
+    /// Stops the playback and discards the queued audio.
+    fn stop(&self);
+    /// Blocks until all the queued audio has been played.
+    fn sleep_until_end(&self);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Play the audio on the default audio device.
+    Rodio,
+    /// Discard the audio, but take as long as playing it would.
+    Null,
+    /// Write the audio into a WAV file.
+    File,
+    /// Write the audio to stdout as raw 16-bit little-endian PCM (unix only).
+    Stdout,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub backend: Backend,
+    /// The file the audio is written to by the file backend.
+    pub file: PathBuf,
+    /// How often the player checks whether the reply has been played out.
+    pub interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: Backend::Rodio,
+            file: PathBuf::from(format!("{}.wav", BOT_NAME)),
+            interval: Duration::from_millis(AUDIO_INTERVAL),
+        }
+    }
+}
+
+/// Opens the audio output; the rodio backend plays the audio on the given device.
+pub fn open(c: &Config, device: Option<&OutputStreamHandle>) -> Result<Box<dyn Output>> {
+    match c.backend {
+        Backend::Rodio => {
+            let device = device.ok_or("no audio device available")?;
+            Ok(Box::new(Rodio::new(device)?))
+        }
+        Backend::Null => Ok(Box::<Null>::default()),
+        Backend::File => Ok(Box::new(Wav::new(c.file.clone()))),
+        Backend::Stdout => Ok(Box::new(Stdout::new()?)),
+    }
+}
+
+pub struct Rodio {
+    sink: Sink,
+}
+
+impl Rodio {
+    pub fn new(device: &OutputStreamHandle) -> Result<Self> {
+        Ok(Rodio {
+            sink: Sink::try_new(device)?,
+        })
+    }
+}
+
+impl Output for Rodio {
+    fn append(&self, channels: u16, sample_rate: u32, samples: Vec<f32>) -> Result<()> {
+        self.sink
+            .append(SamplesBuffer::new(channels, sample_rate, samples));
+        Ok(())
+    }
+
+    fn empty(&self) -> bool {
+        self.sink.empty()
+    }
+
+    fn stop(&self) {
+        self.sink.stop()
+    }
+
+    fn sleep_until_end(&self) {
+        self.sink.sleep_until_end()
+    }
+}
+
+/// Null discards the audio, but keeps track of when it would have finished
+/// playing so the bots take turns at the same pace as with an audio device.
+#[derive(Default)]
+pub struct Null {
+    ends_at: Mutex<Option<Instant>>,
+}
+
+impl Output for Null {
+    fn append(&self, channels: u16, sample_rate: u32, samples: Vec<f32>) -> Result<()> {
+        let frames = samples.len() as f64 / channels.max(1) as f64;
+        let duration = Duration::from_secs_f64(frames / sample_rate.max(1) as f64);
+        let now = Instant::now();
+        let mut ends_at = self.ends_at.lock().unwrap();
+        let start = ends_at.filter(|t| *t > now).unwrap_or(now);
+        *ends_at = Some(start + duration);
+        Ok(())
+    }
+
+    fn empty(&self) -> bool {
+        self.ends_at
+            .lock()
+            .unwrap()
+            .is_none_or(|t| t <= Instant::now())
+    }
+
+    fn stop(&self) {
+        *self.ends_at.lock().unwrap() = None;
+    }
+
+    fn sleep_until_end(&self) {
+        let ends_at = *self.ends_at.lock().unwrap();
+        if let Some(t) = ends_at {
+            thread::sleep(t.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+/// Wav writes the audio into a 16-bit PCM WAV file as fast as it's decoded.
+pub struct Wav {
+    path: PathBuf,
+    writer: Mutex<Option<hound::WavWriter<BufWriter<File>>>>,
+}
+
+impl Wav {
+    pub fn new(path: PathBuf) -> Self {
+        Wav {
+            path,
+            writer: Mutex::new(None),
+        }
+    }
+}
+
+impl Output for Wav {
+    fn append(&self, channels: u16, sample_rate: u32, samples: Vec<f32>) -> Result<()> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        // NOTE: the WAV file format is set by the first audio we receive.
+        if writer.is_none() {
+            println!("writing audio to {}", self.path.display());
+            *writer = Some(hound::WavWriter::create(&self.path, spec)?);
+        }
+        let w = writer.as_mut().ok_or("audio file not open")?;
+        if w.spec() != spec {
+            return Err("audio format changed mid-stream".into());
+        }
+        for s in samples {
+            w.write_sample(pcm16(s))?;
+        }
+        // NOTE: this also updates the WAV header so the file
+        // is playable even if the bot is killed.
+        w.flush()?;
+        Ok(())
+    }
+
+    fn empty(&self) -> bool {
+        true
+    }
+
+    fn stop(&self) {}
+
+    fn sleep_until_end(&self) {}
+}
+
+/// Stdout writes the audio to stdout as raw PCM, e.g. to be piped into aplay:
+/// rustbot --audio-output stdout | aplay -f S16_LE -r 24000 -c 1
+pub struct Stdout {
+    out: &'static Mutex<File>,
+    format: Mutex<Option<(u16, u32)>>,
+}
+
+impl Stdout {
+    pub fn new() -> Result<Self> {
+        Ok(Stdout {
+            out: pcm_stdout()?,
+            format: Mutex::new(None),
+        })
+    }
+}
+
+impl Output for Stdout {
+    fn append(&self, channels: u16, sample_rate: u32, samples: Vec<f32>) -> Result<()> {
+        let mut format = self.format.lock().unwrap();
+        if *format != Some((channels, sample_rate)) {
+            eprintln!(
+                "writing s16le PCM audio to stdout: {} Hz, {} channel(s)",
+                sample_rate, channels
+            );
+            *format = Some((channels, sample_rate));
+        }
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for s in samples {
+            data.extend_from_slice(&pcm16(s).to_le_bytes());
+        }
+        // NOTE: the write blocks for as long as the reader on the other end
+        // takes to play the audio, which paces the conversation.
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&data)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn empty(&self) -> bool {
+        true
+    }
+
+    fn stop(&self) {}
+
+    fn sleep_until_end(&self) {}
+}
+
+/// Returns the writer of the original stdout. The logs printed to stdout
+/// are redirected to stderr so they don't get mixed in with the audio.
+#[cfg(unix)]
+pub fn pcm_stdout() -> Result<&'static Mutex<File>> {
+    use std::{io, os::fd::AsFd, sync::OnceLock};
+
+    static STDOUT: OnceLock<Mutex<File>> = OnceLock::new();
+    if let Some(out) = STDOUT.get() {
+        return Ok(out);
+    }
+    io::stdout().flush()?;
+    let fd = io::stdout().as_fd().try_clone_to_owned()?;
+    // SAFETY: both file descriptors stay open for the lifetime of the process.
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(STDOUT.get_or_init(|| Mutex::new(File::from(fd))))
+}
+
+/// NOTE: the logs can't be moved off stdout portably, so the raw PCM
+/// output is only available on unix.
+#[cfg(not(unix))]
+pub fn pcm_stdout() -> Result<&'static Mutex<File>> {
+    Err("--audio-output stdout is only supported on unix, use --audio-output file instead".into())
+}
+
+fn pcm16(s: f32) -> i16 {
+    (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
@@ -0,0 +1,163 @@
+use crate::{config, history, llm, prelude::*};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Persona bundles the personality of the bot; it's loaded from a TOML file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Persona {
+    /// Bot name.
+    pub name: Option<String>,
+    /// Instruction prompt; it may refer to the template variables.
+    pub prompt: Option<String>,
+    /// Example exchanges which show the LLM how the bot talks.
+    #[serde(default)]
+    pub examples: Vec<Example>,
+    pub voice_id: Option<String>,
+    pub model_name: Option<String>,
+    /// LLM generation options.
+    #[serde(default)]
+    pub options: llm::Options,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Example {
+    pub prompt: String,
+    pub reply: String,
+}
+
+/// Vars are the template variables substituted in the persona prompts.
+#[derive(Clone, Debug, Default)]
+pub struct Vars {
+    /// The bot name.
+    pub name: String,
+    /// Who the bot talks to.
+    pub peer_name: String,
+    /// What the conversation is about.
+    pub topic: Option<String>,
+}
+
+impl Persona {
+    /// Returns the values of the flags set by the persona.
+    pub fn flags(&self) -> Vec<(&'static str, String)> {
+        [
+            ("name", &self.name),
+            ("seed", &self.prompt),
+            ("voice_id", &self.voice_id),
+            ("model_name", &self.model_name),
+        ]
+        .into_iter()
+        .filter_map(|(id, v)| v.clone().map(|v| (id, v)))
+        .collect()
+    }
+
+    /// Returns the examples as chat history messages.
+    pub fn examples(&self, vars: &Vars) -> Result<Vec<history::Message>> {
+        let mut messages = Vec::new();
+        for e in &self.examples {
+            messages.push(history::Message::user(render(&e.prompt, vars)?));
+            messages.push(history::Message::assistant(render(&e.reply, vars)?));
+        }
+        Ok(messages)
+    }
+}
+
+/// Loads the persona; it's either a path to the persona file,
+/// or the name of the persona file found in the directory.
+pub fn load(dir: &Path, name: &str) -> Result<Persona> {
+    let path = Path::new(name);
+    if path.extension().is_some() || path.components().count() > 1 {
+        return config::read(path);
+    }
+    find(dir, name)?
+        .ok_or_else(|| format!("persona {} not found in {}", name, dir.display()).into())
+}
+
+/// Finds the persona file named after the persona in the directory, if there's any.
+pub fn find(dir: &Path, name: &str) -> Result<Option<Persona>> {
+    let path = dir.join(format!("{}.toml", name));
+    path.is_file().then(|| config::read(&path)).transpose()
+}
+
+/// Substitutes the template variables in the text; {{name}} stands for the literal {name}.
+/// Any other braces are left as they are, e.g. the code snippets in the prompts.
+pub fn render(text: &str, vars: &Vars) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('{') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(var) = variable(rest, "{{", "}}") {
+            out.push_str(&rest[1..var.len() + 3]);
+            rest = &rest[var.len() + 4..];
+            continue;
+        }
+        let Some(var) = variable(rest, "{", "}") else {
+            out.push('{');
+            rest = &rest[1..];
+            continue;
+        };
+        let value = match var {
+            "name" => &vars.name,
+            "peer_name" => &vars.peer_name,
+            _ => vars
+                .topic
+                .as_ref()
+                .ok_or("the persona talks about {topic}, but no topic has been given")?,
+        };
+        out.push_str(value);
+        rest = &rest[var.len() + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Returns the template variable the text starts with, enclosed in the given braces.
+fn variable(text: &str, open: &str, close: &str) -> Option<&'static str> {
+    let var = text.strip_prefix(open)?;
+    ["name", "peer_name", "topic"]
+        .into_iter()
+        .find(|v| var.strip_prefix(v).is_some_and(|r| r.starts_with(close)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(topic: Option<&str>) -> Vars {
+        Vars {
+            name: "rustbot".to_string(),
+            peer_name: "gobot".to_string(),
+            topic: topic.map(String::from),
+        }
+    }
+
+    #[test]
+    fn substitutes_the_variables() {
+        let text = "I'm {name}, talking to {peer_name} about {topic}.";
+        assert_eq!(
+            render(text, &vars(Some("errors"))).unwrap(),
+            "I'm rustbot, talking to gobot about errors."
+        );
+    }
+
+    #[test]
+    fn leaves_other_braces_alone() {
+        let text = "fn main() {} {unknown} } {{ {name";
+        assert_eq!(render(text, &vars(None)).unwrap(), text);
+    }
+
+    #[test]
+    fn escapes_the_variables() {
+        assert_eq!(
+            render("{{name}} is {name}", &vars(None)).unwrap(),
+            "{name} is rustbot"
+        );
+    }
+
+    #[test]
+    fn requires_the_topic() {
+        assert!(render("about {topic}", &vars(None)).is_err());
+    }
+}
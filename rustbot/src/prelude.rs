@@ -1,12 +1,45 @@
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-pub const HISTORY_SIZE: usize = 50;
+pub const DEFAULT_CONTEXT_SIZE: usize = 2048;
+// NOTE: context sizes the models were trained with, matched by model name prefix.
+pub const MODEL_CONTEXT_SIZES: &[(&str, usize)] = &[
+    ("llama2", 4096),
+    ("llama3", 8192),
+    ("mistral", 8192),
+    ("gemma", 8192),
+    ("phi3", 4096),
+];
+pub const REPLY_TOKEN_RESERVE: usize = 256;
+pub const MESSAGE_TOKEN_OVERHEAD: usize = 4;
 pub const DEFAULT_MODEL_NAME: &str = "llama2:latest";
+pub const OLLAMA_DEFAULT_URL: &str = "http://localhost:11434";
+pub const OLLAMA_DEFAULT_PORT: u16 = 11434;
+pub const LLM_RETRIES: u32 = 2;
+pub const LLM_RETRY_BACKOFF: u64 = 1000;
+pub const LLM_TIMEOUT: u64 = 60;
 pub const NATS_DEFAULT_URL: &str = "nats://localhost:4222";
+pub const NATS_CONNECT_TIMEOUT: u64 = 5;
+pub const NATS_RECONNECT_BACKOFF: u64 = 100;
+pub const NATS_MAX_RECONNECT_BACKOFF: u64 = 8000;
 pub const STREAM_NAME: &str = "banter";
 pub const BOT_NAME: &str = "rustbot";
 pub const BOT_SUB_SUBJECT: &str = "rust";
 pub const BOT_PUB_SUBJECT: &str = "go";
+pub const ENVELOPE_VERSION: u32 = 1;
+// NOTE: names of the message headers carrying the envelope metadata along with the raw text.
+pub const HEADER_VERSION: &str = "Banter-Version";
+pub const HEADER_CONVERSATION_ID: &str = "Banter-Conversation-Id";
+pub const HEADER_SENDER: &str = "Banter-Sender";
+pub const HEADER_TURN: &str = "Banter-Turn";
+pub const HEADER_TIMESTAMP: &str = "Banter-Timestamp";
+pub const HEADER_MODEL: &str = "Banter-Model";
+pub const HEADER_NEXT: &str = "Banter-Next";
+pub const HEADER_END: &str = "Banter-End";
+pub const PEER_BOT_NAME: &str = "gobot";
+pub const PERSONA_DIR: &str = "personas";
+pub const ROUND_TABLE_SUBJECT: &str = "banter";
+pub const ROUND_TABLE_CONVERSATION_ID: &str = "roundtable";
+pub const ROUND_TABLE_OPERATOR: &str = "operator";
 
 pub const DEFAULT_SEED_PROMPT: &str = "You are a Rust programming language expert \
     and a helpful AI assistant trying to learn about Go programming language. \
@@ -18,11 +51,81 @@ pub const DEFAULT_SEED_PROMPT: &str = "You are a Rust programming language exper
     Question: What is the biggest strength of Rust?
 Assistant: Rust's biggest strength lies in its focus on safety, particularly memory \
 safety, without sacrificing performance. Can you tell me what are some of the biggest \
-strengths of Go that make it stand out from other programming languages?
-Question: ";
+strengths of Go that make it stand out from other programming languages?";
+
+pub const DEFAULT_PEER_SEED_PROMPT: &str = "You are a Go programming language expert \
+    and a helpful AI assistant trying to learn about Rust programming language. \
+    You will answer questions ONLY about Go and ONLY ask questions about Rust. \
+    You do NOT explain how Rust works. You are NOT Rust expert. You ONLY compare Rust \
+    to Go. When you receive a response you will evaluate it from an experienced Go \
+    programmer point of view and ask followup questions about Rust. You must NEVER use \
+    emojis in your answers. Your answers must NOT be longer than 100 words!
+Question: What is the biggest strength of Go?
+Assistant: One of the biggest strengths of Go is its concise syntax and simple grammar, \
+which makes it easy to write code quickly. Can you tell me what are some of the biggest \
+strengths of Rust that make it stand out from other programming languages?";
+
+pub const ROUND_TABLE_SEED_PROMPT: &str = "You are a programming language expert \
+    and a helpful AI assistant taking part in a discussion about programming languages. \
+    You share your experience with your favourite programming language and ask the others \
+    about theirs. NEVER use emojis in your answers! Your answers must NOT be longer than 100 words!";
+pub const NEXT_SPEAKER_PROMPT: &str = "Who of {participants} should speak next in the \
+    discussion? Answer with their name only.";
+
+pub const KICKSTART_PROMPT: &str = "Nobody has said anything for a while. \
+    Start the conversation, or carry it on if it has already started.";
+
+pub const GOODBYE_NOTE: &str =
+    "When the conversation has run its course, end it by saying goodbye.";
+pub const GOODBYE_PHRASES: &[&str] = &[
+    "goodbye",
+    "good bye",
+    "farewell",
+    "bye for now",
+    "talk to you later",
+    "until next time",
+];
+pub const END_OF_CONVERSATION: &str = "End of conversation";
+
+pub const SUMMARY_PROMPT: &str = "You summarize conversations. Given the summary of \
+    the conversation so far and the messages which followed it, write a new summary of \
+    the whole conversation. Keep it short, factual and no longer than 100 words.";
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+
+pub const INTERRUPTED_SUFFIX: &str = "—";
+pub const CANNED_REPLY: &str = "Sorry, I didn't quite catch that. Could you say that again?";
+pub const DEFAULT_KICKOFF_PROMPT: &str = "Hi Gobot! What is the biggest strength of Go?";
+pub const ROUND_TABLE_KICKOFF_PROMPT: &str =
+    "Welcome to the round table! What is the biggest strength of your favourite programming language?";
+
+pub const DEFAULT_SCRIPT: &[&str] = &[
+    "Rust guarantees memory safety at compile time through ownership and borrowing. \
+    How does Go keep programs memory safe without a borrow checker?",
+    "Rust uses async functions and executors like tokio for concurrency. \
+    What makes goroutines so cheap compared to operating system threads?",
+    "Rust models errors with the Result type and the question mark operator. \
+    Why did Go settle on returning error values instead?",
+    "Rust traits are implemented explicitly for every type. \
+    How do Go interfaces get satisfied without an implements keyword?",
+];
 
 pub const DEFAULT_VOICE_ID: &str =
     "s3://voice-cloning-zero-shot/b3def996-302e-486f-a234-172fa0279f0e/anthonysaad/manifest.json";
+pub const DEFAULT_PEER_VOICE_ID: &str =
+    "s3://mockingbird-prod/abigail_vo_6661b91f-4012-44e3-ad12-589fbdee9948/voices/speaker/manifest.json";
 pub const MAX_TTS_BUFFER_SIZE: usize = 1000;
-pub const AUDIO_BUFFER_SIZE: usize = 1024 * 10;
+pub const TTS_MAX_LATENCY: u64 = 1500;
+pub const TTS_SAMPLE_RATE: i32 = 24000;
+pub const MIN_TTS_SAMPLE_RATE: i64 = 8000;
+pub const MAX_TTS_SAMPLE_RATE: i64 = 48000;
+pub const TTS_RETRIES: u32 = 2;
+pub const TTS_RETRY_BACKOFF: u64 = 500;
+pub const TTS_TIMEOUT: u64 = 30;
+pub const TONE_AMPLITUDE: f32 = 0.3;
+pub const WAV_HEADER_SIZE: usize = 44;
 pub const AUDIO_INTERVAL: u64 = 200;
+pub const TURN_TIMEOUT: u64 = 300;
+pub const GRACE_PERIOD: u64 = 10;
+pub const RESTART_BACKOFF: u64 = 500;
+pub const MAX_RESTART_BACKOFF: u64 = 30000;
+pub const MAX_RESTARTS: u32 = 5;
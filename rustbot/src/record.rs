@@ -0,0 +1,193 @@
+use crate::{envelope::Conversation, prelude::*};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::io::{self, AsyncWrite};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory the recordings are written to.
+    pub dir: PathBuf,
+    /// Also stitch all the turns into a single conversation recording.
+    pub stitch: bool,
+}
+
+/// Recorder writes the synthesized speech of every turn the bot takes into
+/// its own file, named after the turn number, e.g. rustbot-turn-0003.mp3.
+/// The format of the recording is that of the synthesized audio: MP3 or WAV.
+pub struct Recorder {
+    config: Config,
+    name: String,
+    conv: Arc<Mutex<Conversation>>,
+    turn: Option<(u64, Writer)>,
+    conversation: Option<Writer>,
+}
+
+impl Recorder {
+    pub fn new(c: Config, name: String, conv: Arc<Mutex<Conversation>>) -> Result<Self> {
+        fs::create_dir_all(&c.dir)?;
+        Ok(Recorder {
+            config: c,
+            name,
+            conv,
+            turn: None,
+            conversation: None,
+        })
+    }
+
+    /// Records a self-contained piece of the synthesized audio.
+    pub fn record(&mut self, audio: &[u8]) -> Result<()> {
+        if audio.is_empty() {
+            return Ok(());
+        }
+        if self.turn.is_none() {
+            // NOTE: the turn is taken only once the reply has been played,
+            // but it's always the one following the last turn we've seen.
+            let turn = self.conv.lock().unwrap().turn + 1;
+            let path = self
+                .config
+                .dir
+                .join(format!("{}-turn-{:04}", self.name, turn));
+            self.turn = Some((turn, Writer::create(&path, audio)?));
+        }
+        if let Some((_, w)) = self.turn.as_mut() {
+            w.write(audio)?;
+        }
+        if self.config.stitch {
+            if self.conversation.is_none() {
+                let path = self.config.dir.join(format!("{}-conversation", self.name));
+                self.conversation = Some(Writer::create(&path, audio)?);
+            }
+            if let Some(w) = self.conversation.as_mut() {
+                w.write(audio)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the recording of the current turn.
+    pub fn end_turn(&mut self) -> Result<()> {
+        if let Some((turn, w)) = self.turn.take() {
+            let path = w.finish()?;
+            println!("recorded turn #{} to {}", turn, path.display());
+        }
+        Ok(())
+    }
+
+    /// Finishes all the recordings.
+    pub fn finish(mut self) -> Result<()> {
+        self.end_turn()?;
+        if let Some(w) = self.conversation.take() {
+            let path = w.finish()?;
+            println!("recorded conversation to {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+enum Writer {
+    Mp3(PathBuf, BufWriter<File>),
+    Wav(PathBuf, hound::WavWriter<BufWriter<File>>),
+}
+
+impl Writer {
+    /// Creates the recording file at the given path, without extension,
+    /// in the format of the given audio.
+    fn create(path: &Path, audio: &[u8]) -> Result<Self> {
+        if audio.starts_with(b"RIFF") {
+            let spec = hound::WavReader::new(Cursor::new(audio))?.spec();
+            let path = path.with_extension("wav");
+            let w = hound::WavWriter::create(&path, spec)?;
+            return Ok(Writer::Wav(path, w));
+        }
+        let path = path.with_extension("mp3");
+        let w = BufWriter::new(File::create(&path)?);
+        Ok(Writer::Mp3(path, w))
+    }
+
+    fn write(&mut self, audio: &[u8]) -> Result<()> {
+        match self {
+            // NOTE: MP3 streams can simply be concatenated.
+            Writer::Mp3(_, w) => w.write_all(audio)?,
+            // NOTE: WAV files can't, so we copy over their samples.
+            Writer::Wav(_, w) => {
+                let mut r = hound::WavReader::new(Cursor::new(audio))?;
+                if r.spec() != w.spec() {
+                    return Err("audio format changed mid-recording".into());
+                }
+                match r.spec().sample_format {
+                    hound::SampleFormat::Float => {
+                        for s in r.samples::<f32>() {
+                            w.write_sample(s?)?;
+                        }
+                    }
+                    hound::SampleFormat::Int => {
+                        for s in r.samples::<i32>() {
+                            w.write_sample(s?)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PathBuf> {
+        match self {
+            Writer::Mp3(path, mut w) => {
+                w.flush()?;
+                Ok(path)
+            }
+            Writer::Wav(path, w) => {
+                w.finalize()?;
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// Tee passes the audio through to the inner writer and keeps a copy of it.
+pub struct Tee<'a, W: ?Sized> {
+    inner: &'a mut W,
+    data: Vec<u8>,
+}
+
+impl<'a, W: AsyncWrite + Unpin + ?Sized> Tee<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Tee {
+            inner,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for Tee<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.data.extend_from_slice(&buf[..n]);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
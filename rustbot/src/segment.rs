@@ -0,0 +1,257 @@
+use std::str;
+
+const SENTENCE_ENDS: [char; 4] = ['.', '!', '?', '…'];
+const CLAUSE_ENDS: [char; 4] = [',', ';', ':', '—'];
+const CLOSERS: [char; 5] = ['"', '\'', ')', ']', '”'];
+
+/// Segmenter splits streamed text into segments which are natural to speak.
+/// Segments end on sentence boundaries; when a sentence grows past the max size
+/// it's split on the last clause boundary or, failing that, on the last word.
+/// Multi-byte UTF-8 characters split across chunks are reassembled.
+pub struct Segmenter {
+    text: String,
+    partial: Vec<u8>,
+    max_size: usize,
+}
+
+impl Segmenter {
+    pub fn new(max_size: usize) -> Self {
+        Segmenter {
+            text: String::new(),
+            partial: Vec::new(),
+            max_size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.trim().is_empty() && self.partial.is_empty()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        loop {
+            match str::from_utf8(&self.partial) {
+                Ok(s) => {
+                    self.text.push_str(s);
+                    self.partial.clear();
+                    return;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    self.text
+                        .push_str(str::from_utf8(&self.partial[..valid]).unwrap_or_default());
+                    match e.error_len() {
+                        // NOTE: the rest of the character arrives with the next chunk.
+                        None => {
+                            self.partial.drain(..valid);
+                            return;
+                        }
+                        Some(n) => {
+                            self.text.push(char::REPLACEMENT_CHARACTER);
+                            self.partial.drain(..valid + n);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the next complete segment if there is one.
+    pub fn next_segment(&mut self) -> Option<String> {
+        // NOTE: the blank segments, e.g. the line breaks between paragraphs, are skipped.
+        loop {
+            let end = match sentence_end(&self.text) {
+                Some(end) => end,
+                None if !self.text.is_empty() && self.text.len() >= self.max_size => {
+                    let mut limit = self.max_size;
+                    while !self.text.is_char_boundary(limit) {
+                        limit -= 1;
+                    }
+                    let head = &self.text[..limit];
+                    clause_end(head).or_else(|| word_end(head)).unwrap_or(limit)
+                }
+                None => return None,
+            };
+            if let Some(segment) = self.take(end) {
+                return Some(segment);
+            }
+            if end == 0 {
+                return None;
+            }
+        }
+    }
+
+    /// Returns the text up to the last clause or word boundary,
+    /// keeping back the word which may still be incomplete.
+    pub fn flush_partial(&mut self) -> Option<String> {
+        let end = clause_end(&self.text).or_else(|| word_end(&self.text))?;
+        self.take(end)
+    }
+
+    /// Discards all the remaining text.
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.partial.clear();
+    }
+
+    /// Returns all the remaining text.
+    pub fn flush(&mut self) -> Option<String> {
+        if !self.partial.is_empty() {
+            let rest = String::from_utf8_lossy(&self.partial).into_owned();
+            self.text.push_str(&rest);
+            self.partial.clear();
+        }
+        self.take(self.text.len())
+    }
+
+    fn take(&mut self, end: usize) -> Option<String> {
+        let rest = self.text.split_off(end);
+        let segment = std::mem::replace(&mut self.text, rest);
+        let segment = segment.trim();
+        (!segment.is_empty()).then(|| segment.to_string())
+    }
+}
+
+/// Returns the end of the first sentence in the text: a sentence terminator,
+/// optionally followed by closing quotes or brackets, followed by whitespace.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\n' {
+            return Some(i + 1);
+        }
+        if !SENTENCE_ENDS.contains(&c) {
+            continue;
+        }
+        while chars.next_if(|(_, c)| CLOSERS.contains(c)).is_some() {}
+        if let Some(&(j, c)) = chars.peek() {
+            if c.is_whitespace() {
+                return Some(j);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the end of the last clause in the text.
+fn clause_end(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !CLAUSE_ENDS.contains(&c) {
+            continue;
+        }
+        if let Some(&(j, c)) = chars.peek() {
+            if c.is_whitespace() {
+                end = Some(j);
+            }
+        }
+    }
+    end
+}
+
+/// Returns the end of the last complete word in the text.
+fn word_end(text: &str) -> Option<usize> {
+    let end = text
+        .trim_end_matches(|c: char| !c.is_whitespace())
+        .trim_end()
+        .len();
+    (end > 0).then_some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(seg: &mut Segmenter) -> Vec<String> {
+        std::iter::from_fn(|| seg.next_segment()).collect()
+    }
+
+    #[test]
+    fn splits_on_sentence_boundaries() {
+        let mut seg = Segmenter::new(100);
+        seg.push(b"Hello there! How are you? (Fine.) Thanks");
+        assert_eq!(
+            segments(&mut seg),
+            ["Hello there!", "How are you?", "(Fine.)"]
+        );
+        assert_eq!(seg.flush().as_deref(), Some("Thanks"));
+        assert!(seg.is_empty());
+    }
+
+    #[test]
+    fn skips_blank_segments() {
+        let mut seg = Segmenter::new(100);
+        seg.push(b"First para.\n\nSecond sentence. Third one. Fourth");
+        assert_eq!(
+            segments(&mut seg),
+            ["First para.", "Second sentence.", "Third one."]
+        );
+        assert_eq!(seg.flush().as_deref(), Some("Fourth"));
+
+        seg.push(b"\n\n");
+        assert_eq!(seg.next_segment(), None);
+        assert!(seg.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_sentence_to_end() {
+        let mut seg = Segmenter::new(100);
+        seg.push(b"Is it done.");
+        assert_eq!(seg.next_segment(), None);
+        seg.push(b" Yes.");
+        assert_eq!(seg.next_segment().as_deref(), Some("Is it done."));
+    }
+
+    #[test]
+    fn reassembles_utf8_split_across_chunks() {
+        let text = "Ça marche… Très bien.";
+        let (head, tail) = text.as_bytes().split_at(1);
+        let mut seg = Segmenter::new(100);
+        seg.push(head);
+        assert!(!seg.is_empty());
+        seg.push(tail);
+        assert_eq!(segments(&mut seg), ["Ça marche…"]);
+        assert_eq!(seg.flush().as_deref(), Some("Très bien."));
+    }
+
+    #[test]
+    fn replaces_invalid_utf8() {
+        let mut seg = Segmenter::new(100);
+        seg.push(b"bad \xff byte");
+        assert_eq!(seg.flush().as_deref(), Some("bad \u{fffd} byte"));
+    }
+
+    #[test]
+    fn splits_long_sentences_on_clauses() {
+        let mut seg = Segmenter::new(20);
+        seg.push(b"One, two, three four five six");
+        assert_eq!(seg.next_segment().as_deref(), Some("One, two,"));
+        assert_eq!(seg.flush().as_deref(), Some("three four five six"));
+    }
+
+    #[test]
+    fn splits_long_sentences_on_words() {
+        let mut seg = Segmenter::new(12);
+        seg.push(b"one two three four");
+        assert_eq!(seg.next_segment().as_deref(), Some("one two"));
+        assert_eq!(seg.next_segment(), None);
+        assert_eq!(seg.flush().as_deref(), Some("three four"));
+    }
+
+    #[test]
+    fn splits_long_words_on_char_boundaries() {
+        let mut seg = Segmenter::new(5);
+        seg.push("ééééé".as_bytes());
+        assert_eq!(seg.next_segment().as_deref(), Some("éé"));
+    }
+
+    #[test]
+    fn flushes_partial_text_on_clauses_and_words() {
+        let mut seg = Segmenter::new(100);
+        seg.push(b"Well, maybe we sho");
+        assert_eq!(seg.flush_partial().as_deref(), Some("Well,"));
+        assert_eq!(seg.flush_partial().as_deref(), Some("maybe we"));
+        assert_eq!(seg.flush().as_deref(), Some("sho"));
+    }
+}
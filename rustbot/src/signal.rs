@@ -1,12 +1,113 @@
-use crate::prelude::*;
-use tokio::{self, signal, sync::watch};
+use crate::{bot::Interrupt, prelude::*};
+use std::{io, process, thread};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::{
+    self,
+    sync::watch,
+    time::{self, Duration},
+};
 
-pub async fn trap(done: watch::Sender<bool>) -> Result<()> {
-    tokio::select! {
-        _ = signal::ctrl_c() => {
-            println!("shutting down, received SIGINT signal...");
-            done.send(true)?;
+/// Shutdown is the state of the shutdown the bots follow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Shutdown {
+    #[default]
+    Running,
+    /// The bots finish the sentence they're speaking and wrap the conversation up.
+    Draining,
+    /// The bots stop right away.
+    Now,
+}
+
+/// Signals are the signals which shut the bots down: SIGINT and SIGTERM
+/// on Unix, Ctrl-C elsewhere.
+struct Signals {
+    #[cfg(unix)]
+    sigint: Signal,
+    #[cfg(unix)]
+    sigterm: Signal,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    fn new() -> Result<Self> {
+        Ok(Signals {
+            sigint: signal(SignalKind::interrupt())?,
+            sigterm: signal(SignalKind::terminate())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Result<Self> {
+        Ok(Signals {})
+    }
+
+    /// Waits for the next signal and returns its name.
+    #[cfg(unix)]
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigint.recv() => "SIGINT",
+            _ = self.sigterm.recv() => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> &'static str {
+        // NOTE: once Ctrl-C can't be listened for, we never shut down on our own.
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        "Ctrl-C"
+    }
+}
+
+/// Traps SIGINT and SIGTERM, or Ctrl-C on non-Unix platforms. The first signal
+/// starts a graceful shutdown which turns into an immediate one once the grace
+/// period is over; the second signal exits the process immediately.
+pub async fn trap(shutdown: watch::Sender<Shutdown>, grace: Duration) -> Result<()> {
+    let mut signals = Signals::new()?;
+
+    let name = signals.recv().await;
+    println!(
+        "shutting down, received {} signal; signal again to exit immediately...",
+        name
+    );
+    shutdown.send(Shutdown::Draining)?;
+
+    let grace_over = time::sleep(grace);
+    tokio::pin!(grace_over);
+    loop {
+        tokio::select! {
+            _ = &mut grace_over, if *shutdown.borrow() == Shutdown::Draining => {
+                println!("grace period is over, stopping now...");
+                shutdown.send(Shutdown::Now)?;
+            }
+            _ = signals.recv() => break,
         }
     }
-    Ok(())
+    println!("exiting immediately");
+    process::exit(1);
+}
+
+/// Listens for the operator commands on stdin: an empty line or stop
+/// cuts the replies the bots are giving short.
+// NOTE: tokio recommends reading interactive stdin on a dedicated thread.
+pub fn operator(bots: Vec<watch::Sender<Interrupt>>) {
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            match line.trim() {
+                "" | "stop" => {
+                    println!("operator interrupt");
+                    for bot in &bots {
+                        // NOTE: the bot might have already exited.
+                        let _ = bot.send(Interrupt::Operator);
+                    }
+                }
+                cmd => println!("unknown operator command: {}", cmd),
+            }
+        }
+    });
 }
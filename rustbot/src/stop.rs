@@ -0,0 +1,125 @@
+use crate::{envelope::Envelope, history, prelude::*};
+use std::fmt;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Max number of turns taken in the conversation.
+    pub max_turns: Option<u64>,
+    /// Max wall-clock duration of the conversation.
+    pub max_duration: Option<Duration>,
+    /// Max number of tokens spoken in the conversation.
+    pub max_tokens: Option<usize>,
+    /// End the conversation once somebody says goodbye.
+    pub goodbye: bool,
+}
+
+/// Reason why the conversation has ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+    MaxTurns,
+    MaxDuration,
+    MaxTokens,
+    /// The speaker has said goodbye.
+    Goodbye(String),
+    /// The speaker has ended the conversation.
+    Ended(String),
+    Shutdown,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::MaxTurns => write!(f, "max turns reached"),
+            Reason::MaxDuration => write!(f, "max duration reached"),
+            Reason::MaxTokens => write!(f, "token budget spent"),
+            Reason::Goodbye(sender) => write!(f, "{} said goodbye", sender),
+            Reason::Ended(sender) => write!(f, "{} ended the conversation", sender),
+            Reason::Shutdown => write!(f, "shutting down"),
+        }
+    }
+}
+
+/// Budget keeps track of the conversation and decides when it's over.
+pub struct Budget {
+    config: Config,
+    name: String,
+    started: Instant,
+    /// Turns taken in the conversation, and by the bot.
+    turns: u64,
+    spoken: u64,
+    tokens: usize,
+    reason: Option<Reason>,
+}
+
+impl Budget {
+    pub fn new(c: Config, name: String) -> Self {
+        Budget {
+            config: c,
+            name,
+            started: Instant::now(),
+            turns: 0,
+            spoken: 0,
+            tokens: 0,
+            reason: None,
+        }
+    }
+
+    /// Takes note of the turn taken in the conversation and
+    /// returns the reason the conversation ends, if it does.
+    pub fn record(&mut self, e: &Envelope) -> Option<Reason> {
+        self.turns += 1;
+        if e.sender == self.name {
+            self.spoken += 1;
+        }
+        self.tokens += history::estimate_tokens(&e.text);
+
+        if self.config.max_turns.is_some_and(|n| self.turns >= n) {
+            return Some(Reason::MaxTurns);
+        }
+        if self.config.max_tokens.is_some_and(|n| self.tokens >= n) {
+            return Some(Reason::MaxTokens);
+        }
+        if self.config.goodbye && says_goodbye(&e.text) {
+            return Some(Reason::Goodbye(e.sender.clone()));
+        }
+        None
+    }
+
+    /// Returns when the conversation runs out of time, if ever.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.config.max_duration.map(|d| self.started + d)
+    }
+
+    /// Ends the conversation; the first reason sticks.
+    pub fn end(&mut self, reason: Reason) {
+        self.reason.get_or_insert(reason);
+    }
+
+    pub fn ended(&self) -> Option<&Reason> {
+        self.reason.as_ref()
+    }
+
+    /// Returns the summary of the conversation so far.
+    pub fn summary(&self) -> String {
+        let reason = self
+            .reason
+            .as_ref()
+            .map_or("interrupted".to_string(), Reason::to_string);
+        format!(
+            "conversation ended ({}): {} turns, {} of them by {}, ~{} tokens in {}s",
+            reason,
+            self.turns,
+            self.spoken,
+            self.name,
+            self.tokens,
+            self.started.elapsed().as_secs()
+        )
+    }
+}
+
+/// Returns true if the text says goodbye.
+fn says_goodbye(text: &str) -> bool {
+    let text = text.to_lowercase();
+    GOODBYE_PHRASES.iter().any(|p| text.contains(p))
+}
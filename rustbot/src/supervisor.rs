@@ -0,0 +1,213 @@
+use crate::prelude::*;
+use clap::ValueEnum;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::watch,
+    time::{self, Duration, Instant},
+};
+
+/// Task is a bot worker run by the supervisor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Task {
+    Reader,
+    Writer,
+    Llm,
+    Tts,
+    Audio,
+    Transcript,
+}
+
+impl Task {
+    /// Returns true if the bot can carry on without the task.
+    fn degradable(self) -> bool {
+        matches!(self, Task::Tts | Task::Audio | Task::Transcript)
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().ok_or(fmt::Error)?;
+        write!(f, "{}", name.get_name())
+    }
+}
+
+/// Policy is what the supervisor does when the task fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Policy {
+    /// Restart the task with backoff.
+    Restart,
+    /// Carry on without the task; the bot falls back to text-only
+    /// without TTS or audio, and stops recording the transcript.
+    Degrade,
+    /// Stop the bot.
+    Abort,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Policies overriding the default ones: the transcript is
+    /// degraded on failure and all the other tasks are restarted.
+    pub policies: Vec<(Task, Policy)>,
+    /// Backoff before the first restart; it doubles with every failure in a row.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Max number of restarts in a row before the task is given up on.
+    pub max_restarts: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            policies: Vec::new(),
+            backoff: Duration::from_millis(RESTART_BACKOFF),
+            max_backoff: Duration::from_millis(MAX_RESTART_BACKOFF),
+            max_restarts: MAX_RESTARTS,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the policy of the task; the last one given wins.
+    pub fn policy(&self, task: Task) -> Policy {
+        let policy = self.policies.iter().rev().find(|(t, _)| *t == task);
+        match policy {
+            Some((_, p)) => *p,
+            None if task == Task::Transcript => Policy::Degrade,
+            None => Policy::Restart,
+        }
+    }
+}
+
+/// Parses the task policy given as TASK=POLICY.
+pub fn parse_policy(s: &str) -> std::result::Result<(Task, Policy), String> {
+    let (task, policy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TASK=POLICY, got {:?}", s))?;
+    let task = Task::from_str(task.trim(), true)?;
+    let policy = Policy::from_str(policy.trim(), true)?;
+    if policy == Policy::Degrade && !task.degradable() {
+        return Err(format!("the bot can't carry on without the {} task", task));
+    }
+    Ok((task, policy))
+}
+
+/// Supervisor runs the bot tasks and handles their failures following the task policies.
+#[derive(Clone)]
+pub struct Supervisor {
+    config: Arc<Config>,
+    /// Number of times the tasks have been restarted.
+    restarts: Arc<Mutex<Vec<(Task, u32)>>>,
+}
+
+impl Supervisor {
+    pub fn new(c: Config) -> Self {
+        Supervisor {
+            config: Arc::new(c),
+            restarts: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns the handle which supervises the runs of the task.
+    pub fn supervise(&self, task: Task, done: watch::Receiver<bool>) -> Supervised {
+        Supervised {
+            supervisor: self.clone(),
+            task,
+            done,
+            failures: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn restarted(&self, task: Task) {
+        let mut restarts = self.restarts.lock().unwrap();
+        match restarts.iter_mut().find(|(t, _)| *t == task) {
+            Some((_, n)) => *n += 1,
+            None => restarts.push((task, 1)),
+        }
+    }
+
+    /// Returns how many times the tasks have been restarted, if any were.
+    pub fn report(&self) -> Option<String> {
+        let restarts = self.restarts.lock().unwrap();
+        if restarts.is_empty() {
+            return None;
+        }
+        let counts: Vec<String> = restarts
+            .iter()
+            .map(|(task, n)| format!("{} {}", task, n))
+            .collect();
+        Some(format!("restarts: {}", counts.join(", ")))
+    }
+}
+
+/// Supervised handles the failures of the task it supervises.
+pub struct Supervised {
+    supervisor: Supervisor,
+    task: Task,
+    done: watch::Receiver<bool>,
+    /// Number of failures in a row.
+    failures: u32,
+    started: Instant,
+}
+
+impl Supervised {
+    /// Handles the result of the task run; returns true once the task is due to be
+    /// restarted, which follows its policy and the backoff. Once the task has been
+    /// restarted max_restarts times in a row, it's given up on: it's degraded
+    /// if the bot can carry on without it, otherwise the bot is stopped.
+    pub async fn restart(&mut self, res: Result<()>) -> Result<bool> {
+        let Err(e) = res else {
+            return Ok(false);
+        };
+        // NOTE: once the bot is stopping, the tasks fail as their peers go away.
+        if *self.done.borrow() {
+            return Ok(false);
+        }
+        let (c, task) = (&self.supervisor.config, self.task);
+        // NOTE: the task which has run for a while has recovered from the earlier failures.
+        if self.started.elapsed() >= c.max_backoff {
+            self.failures = 0;
+        }
+        let policy = match c.policy(task) {
+            Policy::Restart if self.failures >= c.max_restarts => {
+                eprintln!("{} has failed {} times in a row", task, self.failures + 1);
+                if task.degradable() {
+                    Policy::Degrade
+                } else {
+                    Policy::Abort
+                }
+            }
+            policy => policy,
+        };
+        match policy {
+            Policy::Abort => Err(format!("{} failed: {}", task, e).into()),
+            Policy::Degrade => {
+                eprintln!("{} failed, carrying on without it: {}", task, e);
+                Ok(false)
+            }
+            Policy::Restart => {
+                let backoff = c
+                    .backoff
+                    .saturating_mul(1 << self.failures.min(16))
+                    .min(c.max_backoff);
+                self.failures += 1;
+                eprintln!(
+                    "{} failed, restarting it in {}ms: {}",
+                    task,
+                    backoff.as_millis(),
+                    e
+                );
+                tokio::select! {
+                    _ = time::sleep(backoff) => {},
+                    _ = self.done.wait_for(|d| *d) => return Ok(false),
+                }
+                self.supervisor.restarted(task);
+                self.started = Instant::now();
+                Ok(true)
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{envelope::Envelope, history, prelude::*, turn};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+use tokio::{self, sync::mpsc::Receiver, sync::watch};
+
+/// Transcript persists conversation turns as JSON lines
+/// and exports them to a Markdown file alongside.
+pub struct Transcript {
+    jsonl: File,
+    markdown: File,
+}
+
+impl Transcript {
+    /// Opens the transcript at the given path for appending; the Markdown
+    /// export is written to the same path with the md extension.
+    /// A truncated last entry left behind by an interrupted write is dropped.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let open = |p: &Path| OpenOptions::new().create(true).append(true).open(p);
+        let mut jsonl = open(path)?;
+        repair(&mut jsonl, path)?;
+        Ok(Transcript {
+            jsonl,
+            markdown: open(&path.with_extension("md"))?,
+        })
+    }
+
+    pub fn write(&mut self, e: &Envelope) -> Result<()> {
+        // NOTE: each entry is written in one go so an interrupted
+        // write leaves at most the last line truncated.
+        let mut line = serde_json::to_vec(e)?;
+        line.push(b'\n');
+        self.jsonl.write_all(&line)?;
+        self.jsonl.flush()?;
+
+        let model = e
+            .model
+            .as_ref()
+            .map(|m| format!(", {}", m))
+            .unwrap_or_default();
+        writeln!(
+            self.markdown,
+            "**{}** (turn {}{}):\n\n{}\n",
+            e.sender, e.turn, model, e.text
+        )?;
+        self.markdown.flush()?;
+        Ok(())
+    }
+}
+
+/// Makes sure the transcript ends with a complete line so that the new entries
+/// aren't appended to the truncated one: the truncated entry is cut off, while
+/// the complete entry which only misses its line break gets one.
+fn repair(jsonl: &mut File, path: &Path) -> Result<()> {
+    let data = fs::read(path)?;
+    let end = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    let tail = &data[end..];
+    if tail.iter().all(u8::is_ascii_whitespace) {
+        return Ok(());
+    }
+    if serde_json::from_slice::<Envelope>(tail).is_ok() {
+        jsonl.write_all(b"\n")?;
+        return Ok(());
+    }
+    println!(
+        "dropping truncated transcript entry at the end of {}",
+        path.display()
+    );
+    jsonl.set_len(end as u64)?;
+    Ok(())
+}
+
+/// Loads the turns recorded in the transcript at the given path.
+/// A truncated last entry left behind by an interrupted write is skipped.
+pub fn load(path: &Path) -> Result<Vec<Envelope>> {
+    let mut entries = Vec::new();
+    let mut lines = BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .peekable();
+    while let Some((i, line)) = lines.next() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let e = serde_json::from_str(&line);
+        if e.is_err() && lines.peek().is_none() {
+            println!(
+                "skipping truncated transcript entry {}:{}",
+                path.display(),
+                i + 1
+            );
+            continue;
+        }
+        let e = e.map_err(|e| {
+            format!(
+                "{}:{}: invalid transcript entry: {}",
+                path.display(),
+                i + 1,
+                e
+            )
+        })?;
+        entries.push(e);
+    }
+    Ok(entries)
+}
+
+/// Returns the chat history of the given bot recorded in the transcript entries.
+/// With attribute set, the turns of the other speakers are attributed to them.
+pub fn history(entries: &[Envelope], bot_name: &str, attribute: bool) -> Vec<history::Message> {
+    entries
+        .iter()
+        .map(|e| {
+            if e.sender == bot_name {
+                history::Message::assistant(e.text.clone())
+            } else if attribute {
+                history::Message::user(turn::attribute(e))
+            } else {
+                history::Message::user(e.text.clone())
+            }
+        })
+        .collect()
+}
+
+pub async fn record(
+    t: &mut Transcript,
+    entries: &mut Receiver<Envelope>,
+    mut done: watch::Receiver<bool>,
+) -> Result<()> {
+    println!("launching transcript recorder");
+    loop {
+        tokio::select! {
+            _ = done.changed() => {
+                if *done.borrow() {
+                    // NOTE: make sure we don't lose the turns which have already been sent.
+                    while let Ok(e) = entries.try_recv() {
+                        t.write(&e)?;
+                    }
+                    return Ok(())
+                }
+            },
+            Some(e) = entries.recv() => {
+                t.write(&e)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(turn: u64) -> Envelope {
+        Envelope {
+            sender: "rustbot".to_string(),
+            turn,
+            ..Envelope::new(format!("turn {}", turn))
+        }
+    }
+
+    /// Appends the start of an entry as left behind by a crash.
+    fn crash(path: &Path) {
+        let line = serde_json::to_vec(&entry(99)).unwrap();
+        let mut f = OpenOptions::new().append(true).open(path).unwrap();
+        f.write_all(&line[..line.len() / 2]).unwrap();
+    }
+
+    fn turns(path: &Path) -> Vec<u64> {
+        load(path).unwrap().iter().map(|e| e.turn).collect()
+    }
+
+    #[test]
+    fn resumes_from_truncated_transcript() {
+        let dir = std::env::temp_dir().join(format!("rustbot-{:016x}", rand::random::<u64>()));
+        let path = dir.join("transcript.jsonl");
+
+        let mut t = Transcript::open(&path).unwrap();
+        t.write(&entry(1)).unwrap();
+        t.write(&entry(2)).unwrap();
+        drop(t);
+
+        crash(&path);
+        assert_eq!(turns(&path), [1, 2]);
+        Transcript::open(&path).unwrap().write(&entry(3)).unwrap();
+
+        crash(&path);
+        assert_eq!(turns(&path), [1, 2, 3]);
+        Transcript::open(&path).unwrap().write(&entry(4)).unwrap();
+        assert_eq!(turns(&path), [1, 2, 3, 4]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_complete_entry_without_line_break() {
+        let dir = std::env::temp_dir().join(format!("rustbot-{:016x}", rand::random::<u64>()));
+        let path = dir.join("transcript.jsonl");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, serde_json::to_vec(&entry(1)).unwrap()).unwrap();
+
+        Transcript::open(&path).unwrap().write(&entry(2)).unwrap();
+        assert_eq!(turns(&path), [1, 2]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
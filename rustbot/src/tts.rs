@@ -1,7 +1,53 @@
-use crate::{buffer, prelude::*};
-use bytes::Bytes;
-use playht_rs::api::{self, stream::TTSStreamReq, tts::Quality};
-use tokio::{self, sync::mpsc::Receiver, sync::watch};
+use crate::{bot::Interrupt, llm::Chunk, prelude::*, record, segment};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use playht_rs::api::{self, stream::TTSStreamReq};
+use std::f32::consts::PI;
+use tokio::{
+    self,
+    io::{self, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::mpsc::{Receiver, Sender},
+    sync::watch,
+    time::{self, Duration, Instant},
+};
+
+/// SpeechSynthesizer synthesizes speech from text and writes the audio into the given writer.
+#[async_trait]
+pub trait SpeechSynthesizer: Send + Sync {
+    async fn synthesize(&self, text: String, w: &mut (dyn AsyncWrite + Unpin + Send))
+        -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Synthesize speech using PlayHT API.
+    #[value(name = "playht")]
+    PlayHT,
+    /// Beep a tone for every word; runs without any external services.
+    Tone,
+}
+
+/// Quality of the synthesized speech; the higher the quality, the slower the synthesis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Quality {
+    Draft,
+    Low,
+    Medium,
+    High,
+    Premium,
+}
+
+impl From<Quality> for api::tts::Quality {
+    fn from(q: Quality) -> Self {
+        match q {
+            Quality::Draft => api::tts::Quality::Draft,
+            Quality::Low => api::tts::Quality::Low,
+            Quality::Medium => api::tts::Quality::Medium,
+            Quality::High => api::tts::Quality::High,
+            Quality::Premium => api::tts::Quality::Premium,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -9,7 +55,17 @@ pub struct Config {
     pub quality: Option<Quality>,
     pub speed: Option<f32>,
     pub sample_rate: Option<i32>,
+    /// Max size of the text segment sent to the synthesizer in bytes.
     pub buf_size: usize,
+    /// Max time the text may wait for its sentence to finish before it's spoken.
+    pub max_latency: Duration,
+    pub backend: Backend,
+    /// Number of times the failed synthesis request is retried.
+    pub retries: u32,
+    /// Backoff before the first retry; it doubles with every retry.
+    pub retry_backoff: Duration,
+    /// Max time the synthesis request may take.
+    pub timeout: Option<Duration>,
 }
 
 impl Default for Config {
@@ -18,73 +74,328 @@ impl Default for Config {
             voice_id: Some(DEFAULT_VOICE_ID.to_string()),
             quality: Some(Quality::Low),
             speed: Some(1.0),
-            sample_rate: Some(24000),
+            sample_rate: Some(TTS_SAMPLE_RATE),
             buf_size: MAX_TTS_BUFFER_SIZE,
+            max_latency: Duration::from_millis(TTS_MAX_LATENCY),
+            backend: Backend::PlayHT,
+            retries: TTS_RETRIES,
+            retry_backoff: Duration::from_millis(TTS_RETRY_BACKOFF),
+            timeout: Some(Duration::from_secs(TTS_TIMEOUT)),
         }
     }
 }
 
-pub struct TTS {
+pub struct PlayHT {
     client: api::Client,
+    req: TTSStreamReq,
+}
+
+impl PlayHT {
+    pub fn new(c: &Config) -> Self {
+        PlayHT {
+            client: api::Client::new(),
+            req: TTSStreamReq {
+                voice: c.voice_id.clone(),
+                quality: c.quality.map(Into::into),
+                speed: c.speed,
+                sample_rate: c.sample_rate,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechSynthesizer for PlayHT {
+    async fn synthesize(
+        &self,
+        text: String,
+        mut w: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<()> {
+        let req = TTSStreamReq {
+            text: Some(text),
+            ..self.req.clone()
+        };
+        self.client.write_audio_stream(&mut w, &req).await?;
+        Ok(())
+    }
+}
+
+/// Tone synthesizes a short sine beep for every word of the text.
+pub struct Tone {
+    sample_rate: u32,
+}
+
+impl Tone {
+    pub fn new(sample_rate: u32) -> Self {
+        Tone { sample_rate }
+    }
+
+    fn render(&self, text: &str) -> Vec<i16> {
+        let ms = |n: u32| (self.sample_rate * n / 1000) as usize;
+        let mut samples = Vec::new();
+        for word in text.split_whitespace() {
+            let chars = word.chars().count() as u32;
+            let pitch = word.chars().map(|c| c as u32).sum::<u32>() % 8;
+            let freq = 220.0 + 40.0 * pitch as f32;
+            let len = ms(60 + 25 * chars.min(12));
+            let fade = ms(5).max(1);
+            for i in 0..len {
+                // NOTE: fade the beep in and out so it doesn't click.
+                let env = (i.min(len - i) as f32 / fade as f32).min(1.0);
+                let t = i as f32 / self.sample_rate as f32;
+                let s = (2.0 * PI * freq * t).sin() * env * TONE_AMPLITUDE;
+                samples.push((s * i16::MAX as f32) as i16);
+            }
+            let pause = if word.ends_with(['.', ',', '!', '?', ';', ':']) {
+                ms(200)
+            } else {
+                ms(60)
+            };
+            samples.resize(samples.len() + pause, 0);
+        }
+        samples
+    }
+}
+
+#[async_trait]
+impl SpeechSynthesizer for Tone {
+    async fn synthesize(
+        &self,
+        text: String,
+        w: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<()> {
+        let samples = self.render(&text);
+        let mut data = wav_header(self.sample_rate, samples.len());
+        for s in samples {
+            data.extend_from_slice(&s.to_le_bytes());
+        }
+        w.write_all(&data).await?;
+        Ok(())
+    }
+}
+
+/// Returns the header of a mono 16-bit PCM WAV file holding the given number of samples.
+fn wav_header(sample_rate: u32, samples: usize) -> Vec<u8> {
+    let data_len = (samples * 2) as u32;
+    let mut h = Vec::with_capacity(WAV_HEADER_SIZE + data_len as usize);
+    h.extend_from_slice(b"RIFF");
+    h.extend_from_slice(&(36 + data_len).to_le_bytes());
+    h.extend_from_slice(b"WAVEfmt ");
+    h.extend_from_slice(&16u32.to_le_bytes());
+    h.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    h.extend_from_slice(&1u16.to_le_bytes()); // mono
+    h.extend_from_slice(&sample_rate.to_le_bytes());
+    h.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    h.extend_from_slice(&2u16.to_le_bytes());
+    h.extend_from_slice(&16u16.to_le_bytes());
+    h.extend_from_slice(b"data");
+    h.extend_from_slice(&data_len.to_le_bytes());
+    h
+}
+
+pub struct TTS {
+    synth: Box<dyn SpeechSynthesizer>,
     config: Config,
 }
 
 impl TTS {
     pub fn new(c: Config) -> TTS {
-        TTS {
-            client: api::Client::new(),
-            config: c,
-        }
+        let synth: Box<dyn SpeechSynthesizer> = match c.backend {
+            Backend::PlayHT => Box::new(PlayHT::new(&c)),
+            Backend::Tone => Box::new(Tone::new(c.sample_rate.unwrap_or(TTS_SAMPLE_RATE) as u32)),
+        };
+        TTS { synth, config: c }
     }
 
-    pub async fn stream<W>(
-        self,
-        mut w: W,
-        mut chunks: Receiver<Bytes>,
+    /// Speaks the reply chunks. The audio of every reply is written
+    /// into its own pipe which is handed over to the audio player.
+    pub async fn stream(
+        &self,
+        replies: &Sender<DuplexStream>,
+        chunks: &mut Receiver<Chunk>,
+        recorder: &mut Option<record::Recorder>,
+        mut interrupt: watch::Receiver<Interrupt>,
         mut done: watch::Receiver<bool>,
-    ) -> Result<()>
-    where
-        W: tokio::io::AsyncWriteExt + Unpin,
-    {
+    ) -> Result<()> {
         println!("launching TTS stream");
-        let mut buf = buffer::Buffer::new(self.config.buf_size);
-        let mut req = TTSStreamReq {
-            voice: self.config.voice_id,
-            quality: self.config.quality,
-            speed: self.config.speed,
-            sample_rate: self.config.sample_rate,
-            ..Default::default()
-        };
+        let mut seg = segment::Segmenter::new(self.config.buf_size);
+        // NOTE: deadline is set whenever there's text waiting to be spoken.
+        let mut deadline: Option<Instant> = None;
+        let mut pipe: Option<DuplexStream> = None;
+        // NOTE: set when the reply is cut short before all its chunks have arrived.
+        let mut discard = false;
 
         loop {
+            let mut cut = false;
             tokio::select! {
+                biased;
                 _ = done.changed() => {
                     if *done.borrow() {
+                        if let Some(r) = recorder.take() {
+                            r.finish()?;
+                        }
                         return Ok(())
                     }
                 },
+                _ = interrupt.changed() => {
+                    cut = pipe.is_some();
+                    discard = cut;
+                },
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if let (Some(text), Some(w)) = (seg.flush_partial(), pipe.as_mut()) {
+                        cut = !self.say(text, w, recorder, &mut interrupt).await?;
+                        discard = cut;
+                    }
+                    deadline = (!seg.is_empty()).then(|| Instant::now() + self.config.max_latency);
+                },
                 Some(chunk) = chunks.recv() => {
-                    if chunk.is_empty() {
-                        let text = String::from_utf8(buf.as_bytes().to_vec())?;
-                        req.text = Some(text);
-                        self.client.write_audio_stream(&mut w, &req).await?;
-                        buf.reset();
+                    if discard {
+                        discard = !matches!(chunk, Chunk::End | Chunk::Interrupted | Chunk::Failed);
                         continue
                     }
-                    match buf.write(chunk.as_ref()) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            let text = String::from_utf8(buf.as_bytes().to_vec())?;
-                            req.text = Some(text);
-                            self.client.write_audio_stream(&mut w, &req).await?;
-                            buf.reset();
-                            let rem = chunk.len() - e.bytes_written;
-                            let chunk_slice = chunk.as_ref();
-                            buf.write(&chunk_slice[rem..])?;
+                    let w = match pipe.as_mut() {
+                        Some(w) => w,
+                        None => {
+                            let (audio_wr, audio_rd) = io::duplex(1024);
+                            if replies.send(audio_rd).await.is_err() {
+                                // NOTE: the audio player is gone, e.g. it has failed for good;
+                                // there's nobody to speak to, so the bot carries on text-only.
+                                return Ok(());
+                            }
+                            pipe.insert(audio_wr)
                         }
+                    };
+                    match chunk {
+                        Chunk::Text(chunk) => {
+                            seg.push(chunk.as_ref());
+                            let mut spoken = false;
+                            while let Some(text) = seg.next_segment() {
+                                if !self.say(text, w, recorder, &mut interrupt).await? {
+                                    cut = true;
+                                    discard = true;
+                                    break;
+                                }
+                                spoken = true;
+                            }
+                            if seg.is_empty() {
+                                deadline = None;
+                            } else if spoken || deadline.is_none() {
+                                deadline = Some(Instant::now() + self.config.max_latency);
+                            }
+                        }
+                        Chunk::End => {
+                            if let Some(text) = seg.flush() {
+                                self.say(text, w, recorder, &mut interrupt).await?;
+                            }
+                            cut = true;
+                        }
+                        Chunk::Interrupted | Chunk::Failed => cut = true,
+                        // NOTE: the next speaker is only of interest to jet::Writer.
+                        Chunk::Next(_) => {}
+                    }
+                }
+            }
+            if cut {
+                // NOTE: closing the pipe lets the audio player know the reply is over.
+                pipe = None;
+                seg.clear();
+                deadline = None;
+                if let Some(r) = recorder.as_mut() {
+                    r.end_turn()?;
+                }
+            }
+        }
+    }
+
+    /// Speaks the text unless interrupted; returns false when interrupted.
+    /// When the bot is shutting down the text is spoken to the end. Once the speech
+    /// synthesis keeps failing, the rest of the turn goes text-only: false is returned.
+    async fn say(
+        &self,
+        text: String,
+        w: &mut DuplexStream,
+        recorder: &mut Option<record::Recorder>,
+        interrupt: &mut watch::Receiver<Interrupt>,
+    ) -> Result<bool> {
+        let speech = self.speak(text, w, recorder);
+        tokio::pin!(speech);
+        let mut shutdown = false;
+        loop {
+            // NOTE: dropping the speech future cancels the in-flight request.
+            tokio::select! {
+                biased;
+                _ = interrupt.changed(), if !shutdown => {
+                    if *interrupt.borrow_and_update() != Interrupt::Shutdown {
+                        return Ok(false);
+                    }
+                    shutdown = true;
+                },
+                res = &mut speech => {
+                    if let Err(e) = res {
+                        eprintln!("Failed to synthesize speech, the rest of the turn goes text-only: {}", e);
+                        return Ok(false);
                     }
+                    return Ok(!shutdown);
+                },
+            }
+        }
+    }
+
+    async fn speak<W>(
+        &self,
+        text: String,
+        w: &mut W,
+        recorder: &mut Option<record::Recorder>,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = 0;
+        loop {
+            // NOTE: the tee tells whether any of the audio has been written; once it has,
+            // the request can't be retried without the listener hearing it twice.
+            let mut tee = record::Tee::new(&mut *w);
+            let res = self.synthesize(text.clone(), &mut tee).await;
+            let audio = tee.into_data();
+            match res {
+                Ok(()) => {
+                    if let Some(r) = recorder {
+                        r.record(&audio)?;
+                    }
+                    return Ok(());
+                }
+                Err(e) if !audio.is_empty() || attempt >= self.config.retries => return Err(e),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to synthesize speech (attempt {} of {}): {}",
+                        attempt + 1,
+                        self.config.retries + 1,
+                        e
+                    );
+                    time::sleep(
+                        self.config
+                            .retry_backoff
+                            .saturating_mul(1 << attempt.min(16)),
+                    )
+                    .await;
+                    attempt += 1;
                 }
             }
         }
     }
+
+    /// Makes a single synthesis request, which times out unless it's done in time.
+    async fn synthesize(
+        &self,
+        text: String,
+        w: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<()> {
+        match self.config.timeout {
+            Some(t) => time::timeout(t, self.synth.synthesize(text, w))
+                .await
+                .unwrap_or_else(|_| Err(format!("timed out after {}s", t.as_secs()).into())),
+            None => self.synth.synthesize(text, w).await,
+        }
+    }
 }
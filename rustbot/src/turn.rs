@@ -0,0 +1,440 @@
+use crate::{envelope::Envelope, history, prelude::*};
+use clap::ValueEnum;
+use std::cmp::Reverse;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Policy {
+    /// The participants speak in the order they're listed in.
+    RoundRobin,
+    /// The moderator addresses who speaks next; everybody else hands the turn back to it.
+    Moderator,
+    /// The LLM of the speaker picks who speaks next.
+    Llm,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Name of the bot taking part in the round table.
+    pub name: String,
+    /// Names of all the participants, including the bot, in the order they speak.
+    pub participants: Vec<String>,
+    pub policy: Policy,
+    /// The participant moderating the round table with the moderator policy.
+    pub moderator: String,
+}
+
+impl Config {
+    /// Returns the round table config of the given bot; the moderator
+    /// defaults to the first participant.
+    pub fn new(
+        name: String,
+        participants: Vec<String>,
+        policy: Policy,
+        moderator: Option<String>,
+    ) -> Result<Self> {
+        if participants.len() < 2 {
+            return Err("the round table needs at least two participants".into());
+        }
+        for (i, p) in participants.iter().enumerate() {
+            token(p)?;
+            if participants[..i].contains(p) {
+                return Err(format!("participant {} is listed more than once", p).into());
+            }
+        }
+        if !participants.contains(&name) {
+            return Err(format!("bot {} is not among the participants", name).into());
+        }
+        let moderator = moderator.unwrap_or_else(|| participants[0].clone());
+        if !participants.contains(&moderator) {
+            return Err(format!("moderator {} is not among the participants", moderator).into());
+        }
+        Ok(Config {
+            name,
+            participants,
+            policy,
+            moderator,
+        })
+    }
+
+    /// Returns the config of another participant of the same round table.
+    pub fn participant(&self, name: &str) -> Self {
+        Config {
+            name: name.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the participant who speaks first.
+    fn first(&self) -> &str {
+        match self.policy {
+            Policy::Moderator => &self.moderator,
+            _ => &self.participants[0],
+        }
+    }
+
+    /// Returns true if the bot is the one to reply to the turn.
+    pub fn addressed(&self, e: &Envelope) -> bool {
+        let next = e.next.as_deref().unwrap_or_else(|| self.first());
+        e.sender != self.name && next == self.name
+    }
+
+    /// Returns the participants other than the bot.
+    fn others(&self) -> Vec<String> {
+        self.participants
+            .iter()
+            .filter(|p| **p != self.name)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the note appended to the seed prompt which tells the bot about the round table.
+    pub fn seed_note(&self) -> String {
+        let mut note = format!(
+            "You are {} in a round table discussion with {}. \
+            The messages of the other participants start with their name.",
+            self.name,
+            self.others().join(", ")
+        );
+        if self.policy == Policy::Moderator {
+            if self.moderator == self.name {
+                note.push_str(
+                    " You moderate the discussion: ALWAYS address the participant \
+                    who should speak next by their name.",
+                );
+            } else {
+                note.push_str(&format!(" {} moderates the discussion.", self.moderator));
+            }
+        }
+        note
+    }
+}
+
+/// Returns the subject the participant publishes its turns to;
+/// the "*" participant matches the turns of all the participants.
+pub fn subject(conversation_id: &str, participant: &str) -> Result<String> {
+    token(conversation_id)?;
+    Ok(format!(
+        "{}.{}.{}",
+        ROUND_TABLE_SUBJECT, conversation_id, participant
+    ))
+}
+
+/// Checks the name can be used as a single subject token.
+fn token(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || ".*>".contains(c)) {
+        return Err(format!("{:?} can't be used in a subject", name).into());
+    }
+    Ok(())
+}
+
+/// Returns the text of the turn attributed to its speaker.
+pub fn attribute(e: &Envelope) -> String {
+    if e.sender.is_empty() {
+        return e.text.clone();
+    }
+    format!("{}: {}", e.sender, e.text)
+}
+
+/// Table keeps track of who speaks at the round table.
+pub struct Table {
+    config: Config,
+    /// The last participant other than the moderator who spoke.
+    last: Option<String>,
+}
+
+impl Table {
+    pub fn new(c: Config) -> Self {
+        Table {
+            config: c,
+            last: None,
+        }
+    }
+
+    pub fn seed_note(&self) -> String {
+        self.config.seed_note()
+    }
+
+    /// Takes note of the turn taken by another participant.
+    pub fn heard(&mut self, e: &Envelope) {
+        if e.sender != self.config.moderator && self.config.participants.contains(&e.sender) {
+            self.last = Some(e.sender.clone());
+        }
+    }
+
+    /// Returns true if the bot is the one to reply to the turn.
+    pub fn addressed(&self, e: &Envelope) -> bool {
+        self.config.addressed(e)
+    }
+
+    /// Picks who speaks after the bot's reply without consulting the LLM.
+    pub fn pick(&self, reply: &str) -> String {
+        let c = &self.config;
+        match c.policy {
+            Policy::RoundRobin | Policy::Llm => after(&c.participants, Some(&c.name)),
+            Policy::Moderator if c.name != c.moderator => c.moderator.clone(),
+            Policy::Moderator => {
+                // NOTE: when nobody is addressed we carry on around the table.
+                let others = c.others();
+                mentioned(reply, &others).unwrap_or_else(|| after(&others, self.last.as_deref()))
+            }
+        }
+    }
+
+    /// Returns the chat history which asks the LLM who speaks after the bot's reply;
+    /// there's nothing to ask unless the LLM picks the next speaker.
+    pub fn ask(&self, mut messages: Vec<history::Message>) -> Option<Vec<history::Message>> {
+        if self.config.policy != Policy::Llm {
+            return None;
+        }
+        messages.push(history::Message::user(
+            NEXT_SPEAKER_PROMPT.replace("{participants}", &self.config.others().join(", ")),
+        ));
+        Some(messages)
+    }
+
+    /// Returns the next speaker the LLM has answered with; when it
+    /// hasn't named any of the participants, they're picked without it.
+    pub fn answered(&self, answer: &str, reply: &str) -> String {
+        mentioned(answer, &self.config.others()).unwrap_or_else(|| self.pick(reply))
+    }
+}
+
+/// Returns the participant following the given one around the table.
+fn after(participants: &[String], name: Option<&str>) -> String {
+    let i = name
+        .and_then(|n| participants.iter().position(|p| p == n))
+        .map_or(0, |i| i + 1);
+    participants[i % participants.len()].clone()
+}
+
+/// Returns the participant mentioned first in the text.
+fn mentioned(text: &str, participants: &[String]) -> Option<String> {
+    let text = text.to_lowercase();
+    participants
+        .iter()
+        .filter_map(|p| {
+            let name = p.to_lowercase();
+            text.match_indices(&name)
+                .find(|(i, _)| {
+                    let before = text[..*i].chars().next_back();
+                    let after = text[i + name.len()..].chars().next();
+                    !before.is_some_and(char::is_alphanumeric)
+                        && !after.is_some_and(char::is_alphanumeric)
+                })
+                .map(|(i, _)| (i, p))
+        })
+        .min_by_key(|(i, _)| *i)
+        .map(|(_, p)| p.clone())
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timing {
+    /// Max time the bot may hold the floor before its reply is cut short.
+    pub turn_timeout: Option<Duration>,
+    /// Time of silence after which the bot takes the floor itself.
+    pub kickstart_after: Option<Duration>,
+    /// Start the conversation instead of waiting for somebody to speak.
+    pub start: bool,
+}
+
+/// Verdict is what the bot makes of a received turn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The turn is stale or has lost a collision; it's ignored.
+    Drop,
+    /// The turn is heard, but somebody else replies to it.
+    Hear,
+    /// The bot takes the floor and replies to the turn.
+    Reply,
+}
+
+/// Expiry is what's due once the coordinator deadline passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiry {
+    /// The bot has held the floor for too long; its reply is cut short.
+    Turn,
+    /// Nobody has spoken for too long; the bot takes the floor itself.
+    Kickstart,
+}
+
+/// Coordinator keeps the bots from talking over each other. The turns are
+/// totally ordered by their number and, when two speakers take the same turn,
+/// by the speaker name: the lesser name wins. The bot holds the floor from the
+/// moment it decides to reply until its reply is published; any turn which
+/// doesn't beat the one the bot is taking meanwhile is dropped, while the one
+/// which does makes the bot abandon its reply. With barge-in, any new turn does.
+pub struct Coordinator {
+    name: String,
+    table: Option<Config>,
+    timing: Timing,
+    barge_in: bool,
+    /// The latest turn taken in the conversation and its speaker.
+    last: (u64, String),
+    /// The turn the bot is taking and when it must be done with it.
+    floor: Option<(u64, Option<Instant>)>,
+    /// When the latest turn was taken.
+    quiet_since: Instant,
+    start: bool,
+}
+
+impl Coordinator {
+    pub fn new(
+        name: String,
+        table: Option<Config>,
+        timing: Timing,
+        barge_in: bool,
+        turn: u64,
+    ) -> Self {
+        Coordinator {
+            name,
+            table,
+            start: timing.start,
+            timing,
+            barge_in,
+            // NOTE: nobody can take the turn we've resumed the conversation from.
+            last: (turn, String::new()),
+            floor: None,
+            quiet_since: Instant::now(),
+        }
+    }
+
+    /// Returns true while the bot is taking its turn.
+    pub fn holds_floor(&self) -> bool {
+        self.floor.is_some()
+    }
+
+    /// Decides what to do about the received turn.
+    pub fn receive(&mut self, e: &Envelope) -> Verdict {
+        let turn = key(e.turn, &e.sender);
+        if turn <= key(self.last.0, &self.last.1) {
+            return Verdict::Drop;
+        }
+        if let Some((n, _)) = self.floor {
+            if !self.barge_in && turn < key(n, &self.name) {
+                return Verdict::Drop;
+            }
+        }
+        self.last = (e.turn, e.sender.clone());
+        self.quiet_since = Instant::now();
+        self.start = false;
+        self.floor = None;
+        if self.table.as_ref().is_some_and(|t| !t.addressed(e)) {
+            return Verdict::Hear;
+        }
+        self.take_floor();
+        Verdict::Reply
+    }
+
+    /// Gives the floor up without replying; returns true if the bot held it.
+    pub fn release(&mut self) -> bool {
+        self.floor.take().is_some()
+    }
+
+    /// Takes note of the turn the bot has published, which hands the floor over.
+    pub fn spoke(&mut self, e: &Envelope) {
+        // NOTE: the bot may have already taken the floor again, while the turn
+        // it has published may be numbered past the one it has taken the floor at.
+        if e.sender == self.name && self.floor.is_some_and(|(n, _)| e.turn >= n) {
+            self.floor = None;
+        }
+        if key(e.turn, &e.sender) > key(self.last.0, &self.last.1) {
+            self.last = (e.turn, e.sender.clone());
+        }
+        self.quiet_since = Instant::now();
+    }
+
+    /// Returns when the next timeout expires, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.floor {
+            Some((_, deadline)) => deadline,
+            None if self.start => Some(Instant::now()),
+            None => self.timing.kickstart_after.map(|t| self.quiet_since + t),
+        }
+    }
+
+    /// Returns what's due if the deadline has passed.
+    pub fn expire(&mut self) -> Option<Expiry> {
+        let deadline = self.deadline()?;
+        if deadline > Instant::now() {
+            return None;
+        }
+        if let Some((n, _)) = self.floor {
+            // NOTE: the floor is handed over once what's been said is published.
+            self.floor = Some((n, None));
+            return Some(Expiry::Turn);
+        }
+        Some(Expiry::Kickstart)
+    }
+
+    /// Takes the floor unprompted and returns the prompt the bot replies to.
+    pub fn kickstart(&mut self) -> Envelope {
+        self.start = false;
+        self.take_floor();
+        Envelope {
+            next: Some(self.name.clone()),
+            ..Envelope::new(KICKSTART_PROMPT.to_string())
+        }
+    }
+
+    fn take_floor(&mut self) {
+        let deadline = self.timing.turn_timeout.map(|t| Instant::now() + t);
+        self.floor = Some((self.last.0 + 1, deadline));
+    }
+}
+
+/// Returns the key the turns are ordered by.
+fn key(turn: u64, sender: &str) -> (u64, Reverse<&str>) {
+    (turn, Reverse(sender))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Conversation;
+
+    fn turn(n: u64, sender: &str) -> Envelope {
+        Envelope {
+            turn: n,
+            sender: sender.to_string(),
+            ..Envelope::new(String::new())
+        }
+    }
+
+    #[test]
+    fn lost_collision_hands_the_floor_over() {
+        let mut coord = Coordinator::new("bob".to_string(), None, Timing::default(), false, 0);
+        let mut conv = Conversation::new(None, 0);
+        for e in [turn(1, "alice"), turn(2, "carol")] {
+            if coord.receive(&e) != Verdict::Drop {
+                conv.receive(&e);
+            }
+        }
+        assert!(coord.holds_floor());
+        assert_eq!(conv.turn, 1);
+
+        let reply = conv.next("bob", None, "hi".to_string());
+        assert_eq!(reply.turn, 2);
+        coord.spoke(&reply);
+        assert!(!coord.holds_floor());
+    }
+
+    #[test]
+    fn later_turn_releases_the_floor() {
+        let mut coord = Coordinator::new("bob".to_string(), None, Timing::default(), false, 0);
+        assert_eq!(coord.receive(&turn(1, "alice")), Verdict::Reply);
+        coord.spoke(&turn(3, "bob"));
+        assert!(!coord.holds_floor());
+    }
+
+    #[test]
+    fn winning_turn_takes_the_floor_over() {
+        let mut coord = Coordinator::new("bob".to_string(), None, Timing::default(), false, 0);
+        assert_eq!(coord.receive(&turn(1, "carol")), Verdict::Reply);
+        assert_eq!(coord.receive(&turn(2, "carol")), Verdict::Drop);
+        assert_eq!(coord.receive(&turn(2, "alice")), Verdict::Reply);
+        // NOTE: the bot is taking the turn after the one it has lost.
+        coord.spoke(&turn(2, "bob"));
+        assert!(coord.holds_floor());
+    }
+}